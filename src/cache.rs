@@ -1,58 +1,509 @@
-use std::collections::HashMap;
-
 use chrono::{DateTime, Duration, Utc};
+use lru::LruCache;
+
+use crate::{config::SwiftConfig, dns, wire};
+
+/// The TTL of expired responses that are served because no upstream could be reached, which is
+/// also how long we wait before trying to refresh them again (RFC 8767 4).
+pub const STALE_TTL: u32 = 30;
 
-use crate::dns;
+/// How often an entry has to be hit before it expires to be refreshed ahead of time.
+const PREFETCH_MIN_HITS: u32 = 3;
+
+/// Hot entries are refreshed once they're within the last 1/n of their TTL.
+const PREFETCH_WINDOW: i32 = 10;
 
 #[derive(Clone)]
 pub struct CacheEntry {
+    pub stored_at: DateTime<Utc>,
     pub valid_until: DateTime<Utc>,
-    pub response: dns::DnsResponse,
+    /// When to try resolving the question again, once it has expired and failed to resolve
+    pub retry_at: Option<DateTime<Utc>>,
+    /// How often the entry has been served since it was stored
+    pub hits: u32,
+    /// Whether the entry is being refreshed before it expires
+    prefetching: bool,
+    /// The response in wire format, with the TTLs as they were when it was stored
+    pub response: Vec<u8>,
+}
+
+impl CacheEntry {
+    pub fn new(stored_at: DateTime<Utc>, valid_until: DateTime<Utc>, response: Vec<u8>) -> Self {
+        CacheEntry {
+            stored_at,
+            valid_until,
+            retry_at: None,
+            hits: 0,
+            prefetching: false,
+            response,
+        }
+    }
+
+    /// Whether the entry is past the point where even a stale response may be served from it.
+    fn is_gone(&self, serve_stale: Duration, now: DateTime<Utc>) -> bool {
+        self.valid_until + serve_stale < now
+    }
+
+    /// Roughly how much memory the entry takes up, along with its key.
+    fn size(&self, question: &dns::DnsQuestion) -> usize {
+        self.response.len() + question.name.len() + std::mem::size_of::<(dns::DnsQuestion, Self)>()
+    }
 }
 
+/// The responses we've seen, up to a limit. When the cache is full, the least recently used
+/// entries make way for new ones.
 pub struct Cache {
-    hash_map: HashMap<dns::DnsQuestion, CacheEntry>,
+    entries: LruCache<dns::DnsQuestion, CacheEntry>,
+    /// The most entries to keep, or 0 for no limit
+    max_entries: usize,
+    /// The most bytes the entries may take up, or 0 for no limit
+    max_bytes: usize,
+    bytes: usize,
+    /// The shortest and longest a response is cached for (in seconds)
+    min_ttl: u32,
+    max_ttl: u32,
+    /// The longest a negative response is cached for (in seconds)
+    negative_ttl: u32,
+    /// How long responses are kept after they expire, to serve when no upstream can be reached
+    serve_stale: Duration,
 }
 
 impl Cache {
-    pub fn new() -> Cache {
-        let hash_map = HashMap::new();
+    pub fn new(config: &SwiftConfig) -> Cache {
+        Cache {
+            entries: LruCache::unbounded(),
+            max_entries: config.cache_max_entries,
+            max_bytes: config.cache_max_bytes,
+            bytes: 0,
+            min_ttl: config.min_ttl,
+            max_ttl: config.max_ttl,
+            negative_ttl: config.negative_ttl,
+            serve_stale: Duration::seconds(config.serve_stale.into()),
+        }
+    }
 
-        Cache { hash_map }
+    pub fn len(&self) -> usize {
+        self.entries.len()
     }
 
-    pub fn set(&mut self, question: dns::DnsQuestion, response: &dns::DnsResponse) {
-        let response = response.clone();
+    fn is_full(&self) -> bool {
+        (self.max_entries > 0 && self.entries.len() > self.max_entries)
+            || (self.max_bytes > 0 && self.bytes > self.max_bytes)
+    }
 
-        // We will assume that the TTL for the first record will be the same for all records in this response.
-        // There are rare edge-cases where this is not necessarily the case, but we can pretend those cases don't exist,
-        // and it's unlikely to cause any issues.
-        let first_answer = response.answer.as_ref().unwrap().get(0);
-        let ttl_seconds = first_answer.unwrap().ttl;
+    /// Returns every entry, from the least to the most recently used one.
+    pub fn entries(&self) -> impl Iterator<Item = (&dns::DnsQuestion, &CacheEntry)> {
+        self.entries.iter().rev()
+    }
 
-        debug!("ttl for `{}` is {} seconds", question.name, ttl_seconds);
+    /// Adds an entry as is, making room for it if the cache is full.
+    pub fn insert(&mut self, question: dns::DnsQuestion, entry: CacheEntry) {
+        self.bytes += entry.size(&question);
 
-        let valid_until = Utc::now() + Duration::seconds(ttl_seconds.into());
+        if let Some((question, replaced)) = self.entries.push(question, entry) {
+            self.bytes -= replaced.size(&question);
+        }
 
-        let entry = CacheEntry {
-            response,
-            valid_until,
+        while self.is_full() {
+            match self.entries.pop_lru() {
+                Some((question, evicted)) => {
+                    self.bytes -= evicted.size(&question);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, question: &dns::DnsQuestion) {
+        if let Some((question, removed)) = self.entries.pop_entry(question) {
+            self.bytes -= removed.size(&question);
+        }
+    }
+
+    /// Removes every entry that has expired (and can't be served stale anymore), returning how many
+    /// there were.
+    pub fn remove_expired(&mut self) -> usize {
+        let now = Utc::now();
+
+        let expired: Vec<dns::DnsQuestion> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.is_gone(self.serve_stale, now))
+            .map(|(question, _)| question.clone())
+            .collect();
+
+        for question in &expired {
+            self.remove(question);
+        }
+
+        expired.len()
+    }
+
+    /// Caches a response for as long as its records may be cached, within the configured `min_ttl`
+    /// and `max_ttl`. Responses that say the name or record doesn't exist are cached as well, for as
+    /// long as their SOA record allows (RFC 2308).
+    ///
+    /// The TTLs of the response are adjusted to how long it's cached for, so clients don't keep it
+    /// any longer (or shorter) than we do.
+    pub fn set(&mut self, question: dns::DnsQuestion, response: &mut [u8]) {
+        let ttl_seconds = match wire::rcode(response) {
+            // The response is only as fresh as its shortest-lived record
+            Ok(wire::RCODE_NO_ERROR)
+                if wire::answer_count(response).is_ok_and(|count| count > 0) =>
+            {
+                let ttl = match wire::min_answer_ttl(response) {
+                    Ok(Some(ttl)) => ttl.max(self.min_ttl).min(self.max_ttl),
+                    _ => return,
+                };
+
+                if wire::clamp_ttls(response, self.min_ttl, self.max_ttl).is_err() {
+                    return;
+                }
+
+                ttl
+            }
+            Ok(wire::RCODE_NO_ERROR | wire::RCODE_NX_DOMAIN) => {
+                let ttl = match wire::negative_ttl(response) {
+                    Ok(Some(ttl)) => ttl.min(self.negative_ttl),
+                    _ => return,
+                };
+
+                // Clients cache the SOA record for the negative response, so it mustn't outlive ours
+                if wire::clamp_ttls(response, 0, ttl).is_err() {
+                    return;
+                }
+
+                ttl
+            }
+            _ => return,
         };
 
-        self.hash_map.insert(question, entry);
+        debug!("ttl for `{}` is {} seconds", question.name, ttl_seconds);
+
+        let stored_at = Utc::now();
+        let valid_until = stored_at + Duration::seconds(ttl_seconds.into());
+
+        self.insert(
+            question,
+            CacheEntry::new(stored_at, valid_until, response.to_vec()),
+        );
     }
 
+    /// Returns the cached response, with the TTLs counted down by the time it has been cached for.
     pub fn get(&mut self, question: &dns::DnsQuestion) -> Option<CacheEntry> {
-        if let Some(entry) = self.hash_map.get(question) {
-            if entry.valid_until < Utc::now() {
-                self.hash_map.remove(question);
+        let now = Utc::now();
+        let entry = self.entries.get_mut(question)?;
 
-                return None;
+        if entry.valid_until < now {
+            if entry.is_gone(self.serve_stale, now) {
+                self.remove(question);
             }
 
-            return Some(entry.clone());
+            return None;
+        }
+
+        entry.hits += 1;
+
+        let mut entry = entry.clone();
+        let elapsed = (now - entry.stored_at).num_seconds();
+
+        wire::age(&mut entry.response, elapsed.try_into().unwrap_or(0)).ok()?;
+
+        Some(entry)
+    }
+
+    /// Returns the cached response if it has expired, but may still be served because no upstream
+    /// can be reached (RFC 8767). Its TTLs are lowered to [`STALE_TTL`].
+    pub fn get_stale(&mut self, question: &dns::DnsQuestion) -> Option<CacheEntry> {
+        let now = Utc::now();
+        let entry = self.entries.get(question)?;
+
+        if entry.valid_until >= now || entry.is_gone(self.serve_stale, now) {
+            return None;
+        }
+
+        let mut entry = entry.clone();
+
+        wire::clamp_ttls(&mut entry.response, 0, STALE_TTL).ok()?;
+
+        Some(entry)
+    }
+
+    /// Whether an entry is hot and close enough to expiring that it should be refreshed now, so it
+    /// never has to be resolved while a client waits. Only returns `true` once for every entry.
+    pub fn needs_prefetch(&mut self, question: &dns::DnsQuestion) -> bool {
+        let Some(entry) = self.entries.peek_mut(question) else {
+            return false;
+        };
+
+        let now = Utc::now();
+        let remaining = entry.valid_until - now;
+        let ttl = entry.valid_until - entry.stored_at;
+
+        if entry.prefetching
+            || entry.hits < PREFETCH_MIN_HITS
+            || remaining > ttl / PREFETCH_WINDOW
+            || remaining < Duration::zero()
+        {
+            return false;
+        }
+
+        entry.prefetching = true;
+
+        true
+    }
+
+    /// Holds off on resolving an expired question again for [`STALE_TTL`] seconds, after it failed.
+    pub fn postpone_retry(&mut self, question: &dns::DnsQuestion) {
+        if let Some(entry) = self.entries.peek_mut(question) {
+            entry.retry_at = Some(Utc::now() + Duration::seconds(STALE_TTL.into()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dns_message_parser::RCode;
+
+    use super::Cache;
+    use crate::{
+        config::SwiftConfig,
+        dns::DnsQuestion,
+        testing::{a, message, soa},
+        wire,
+    };
+
+    fn limited_cache(max_entries: usize, max_bytes: usize) -> Cache {
+        Cache::new(&SwiftConfig {
+            cache_max_entries: max_entries,
+            cache_max_bytes: max_bytes,
+            ..SwiftConfig::default()
+        })
+    }
+
+    fn question(name: &str) -> DnsQuestion {
+        DnsQuestion {
+            name: name.to_owned(),
+            r#type: 1,
+            class: 1,
+            dnssec_ok: false,
+        }
+    }
+
+    fn response(name: &str, ttl: u32) -> Vec<u8> {
+        message(name, RCode::NoError, vec![a(name, ttl)], Vec::new())
+    }
+
+    /// A response without any answers, with an SOA record in the authority section if `soa_ttl`
+    /// is given.
+    fn negative_response(name: &str, rcode: RCode, soa_ttl: Option<u32>) -> Vec<u8> {
+        let authorities = soa_ttl.map(|ttl| soa(ttl, 86400)).into_iter().collect();
+
+        message(name, rcode, Vec::new(), authorities)
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = limited_cache(2, 0);
+
+        cache.set(question("a.example"), &mut response("a.example", 300));
+        cache.set(question("b.example"), &mut response("b.example", 300));
+
+        // Using `a` makes `b` the least recently used one
+        assert!(cache.get(&question("a.example")).is_some());
+
+        cache.set(question("c.example"), &mut response("c.example", 300));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&question("a.example")).is_some());
+        assert!(cache.get(&question("b.example")).is_none());
+        assert!(cache.get(&question("c.example")).is_some());
+    }
+
+    #[test]
+    fn limits_size() {
+        let mut entry = response("a.example", 300);
+        let mut cache = limited_cache(0, 0);
+
+        cache.set(question("a.example"), &mut entry);
+
+        let size = cache.bytes;
+        let mut cache = limited_cache(0, size * 3);
+
+        for name in ["a.example", "b.example", "c.example", "d.example"] {
+            cache.set(question(name), &mut response(name, 300));
+        }
+
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.bytes, size * 3);
+
+        // Replacing an entry doesn't count it twice
+        cache.set(question("d.example"), &mut response("d.example", 60));
+
+        assert_eq!(cache.bytes, size * 3);
+    }
+
+    #[test]
+    fn counts_down_ttls() {
+        let mut cache = limited_cache(0, 0);
+
+        cache.set(question("a.example"), &mut response("a.example", 300));
+
+        let entry = cache.entries.get_mut(&question("a.example")).unwrap();
+        entry.stored_at -= chrono::Duration::seconds(100);
+
+        let cached = cache.get(&question("a.example")).unwrap();
+
+        assert_eq!(wire::min_answer_ttl(&cached.response).unwrap(), Some(200));
+    }
+
+    #[test]
+    fn clamps_ttls() {
+        let mut cache = Cache::new(&SwiftConfig {
+            min_ttl: 60,
+            max_ttl: 600,
+            ..SwiftConfig::default()
+        });
+
+        for (name, ttl, expected) in [("a.example", 20, 60), ("b.example", 3600, 600)] {
+            let mut response = response(name, ttl);
+            cache.set(question(name), &mut response);
+
+            // The client is told the same TTL as what we cache the response for
+            assert_eq!(wire::min_answer_ttl(&response).unwrap(), Some(expected));
+
+            let cached = cache.get(&question(name)).unwrap();
+
+            assert_eq!(
+                wire::min_answer_ttl(&cached.response).unwrap(),
+                Some(expected)
+            );
+            assert_eq!(
+                (cached.valid_until - cached.stored_at).num_seconds(),
+                i64::from(expected)
+            );
         }
+    }
+
+    #[test]
+    fn caches_negative_responses() {
+        let mut cache = Cache::new(&SwiftConfig {
+            negative_ttl: 300,
+            ..SwiftConfig::default()
+        });
+
+        cache.set(
+            question("a.example"),
+            &mut negative_response("a.example", RCode::NXDomain, Some(3600)),
+        );
+        cache.set(
+            question("b.example"),
+            &mut negative_response("b.example", RCode::NoError, Some(60)),
+        );
+        cache.set(
+            question("c.example"),
+            &mut negative_response("c.example", RCode::NXDomain, None),
+        );
+        cache.set(
+            question("d.example"),
+            &mut negative_response("d.example", RCode::ServFail, Some(60)),
+        );
+
+        // The SOA record that is sent along doesn't live longer than the cached response
+        let cached = cache.get(&question("a.example")).unwrap();
+        assert_eq!(wire::negative_ttl(&cached.response).unwrap(), Some(300));
+
+        let cached = cache.get(&question("b.example")).unwrap();
+        assert_eq!(wire::negative_ttl(&cached.response).unwrap(), Some(60));
+
+        assert!(cache.get(&question("c.example")).is_none());
+        assert!(cache.get(&question("d.example")).is_none());
+    }
+
+    #[test]
+    fn serves_stale_entries() {
+        let mut cache = Cache::new(&SwiftConfig {
+            serve_stale: 3600,
+            ..SwiftConfig::default()
+        });
+
+        cache.set(question("a.example"), &mut response("a.example", 300));
+        cache.set(question("b.example"), &mut response("b.example", 300));
+
+        let expire = |cache: &mut Cache, name, seconds| {
+            let entry = cache.entries.get_mut(&question(name)).unwrap();
+            entry.valid_until -= chrono::Duration::seconds(seconds);
+        };
+
+        expire(&mut cache, "a.example", 600);
+        expire(&mut cache, "b.example", 7200);
+
+        assert!(cache.get(&question("a.example")).is_none());
+
+        let stale = cache.get_stale(&question("a.example")).unwrap();
+        assert_eq!(
+            wire::min_answer_ttl(&stale.response).unwrap(),
+            Some(super::STALE_TTL)
+        );
+
+        // Past the window, the entry is gone for good
+        assert!(cache.get_stale(&question("b.example")).is_none());
+        assert_eq!(cache.remove_expired(), 1);
+        assert_eq!(cache.len(), 1);
+
+        // Without serve-stale, expired entries are never served
+        let mut cache = limited_cache(0, 0);
+
+        cache.set(question("a.example"), &mut response("a.example", 300));
+        expire(&mut cache, "a.example", 600);
+
+        assert!(cache.get_stale(&question("a.example")).is_none());
+    }
+
+    #[test]
+    fn prefetches_hot_entries() {
+        let mut cache = limited_cache(0, 0);
+
+        cache.set(question("a.example"), &mut response("a.example", 300));
+        cache.set(question("b.example"), &mut response("b.example", 300));
+
+        for _ in 0..3 {
+            cache.get(&question("a.example"));
+        }
+
+        cache.get(&question("b.example"));
+
+        // Not close enough to expiring yet
+        assert!(!cache.needs_prefetch(&question("a.example")));
+
+        for name in ["a.example", "b.example"] {
+            let entry = cache.entries.get_mut(&question(name)).unwrap();
+            entry.stored_at -= chrono::Duration::seconds(280);
+            entry.valid_until -= chrono::Duration::seconds(280);
+        }
+
+        assert!(cache.needs_prefetch(&question("a.example")));
+        // Only once, while the refresh is underway
+        assert!(!cache.needs_prefetch(&question("a.example")));
+        // Not hit often enough
+        assert!(!cache.needs_prefetch(&question("b.example")));
+
+        // The refreshed entry has to be hit again to be prefetched
+        cache.set(question("a.example"), &mut response("a.example", 300));
+        assert_eq!(cache.get(&question("a.example")).unwrap().hits, 1);
+    }
+
+    #[test]
+    fn removes_expired_entries() {
+        let mut cache = limited_cache(0, 0);
+
+        cache.set(question("a.example"), &mut response("a.example", 0));
+        cache.set(question("b.example"), &mut response("b.example", 300));
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
 
-        None
+        assert_eq!(cache.remove_expired(), 1);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&question("b.example")).is_some());
     }
 }
@@ -1,144 +1,1509 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    error::Error,
+    fmt::{self, Display},
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError},
+};
 
 use chrono::Utc;
-use dns_message_parser::{Dns, RCode};
+use futures_util::future;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    sync::{mpsc, oneshot, Semaphore},
+    time::{self, Duration},
+};
 
 use crate::{
-    cache::Cache,
-    dns::{self, RecordType},
-    domain::Domain,
-    filter,
+    cache::{Cache, CacheEntry},
+    config::{ListenProtocol, ListenerConfig, SwiftConfig},
+    dns::{self, ResolveError},
+    doh, domain,
+    filter::Filter,
+    shutdown,
+    snapshot::Snapshot,
+    systemd::PassedSocket,
+    tls,
+    upstream::Forwarder,
+    wire,
 };
 
-pub async fn start(addr: &SocketAddr, client: reqwest::Client) {
-    let mut cache = Cache::new();
+/// How long an idle TCP connection is kept open while waiting for the next query.
+pub const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
 
-    let socket = match UdpSocket::bind(addr) {
-        Ok(socket) => socket,
-        Err(err) => panic!(
-            "failed to bind listener on addr `{}` ({})",
-            addr.to_string(),
-            err
-        ),
-    };
+/// How many queries on a single TCP connection are answered at once, before reading more of them
+/// waits for one to finish.
+const TCP_MAX_PENDING_QUERIES: usize = 32;
 
-    info!("listening on {addr}");
+/// How long a client waits for the upstreams before it gets the stale response instead, if there is
+/// one. Resolving carries on in the background (RFC 8767 5).
+const STALE_ANSWER_TIMEOUT: Duration = Duration::from_millis(1800);
 
-    loop {
-        let mut buf = [0; 512];
-        let (amt, src) = socket.recv_from(&mut buf).unwrap();
-        let mut query = dns::decode(&buf[..amt]).unwrap();
+/// How often expired responses are removed from the cache.
+const CACHE_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The protocol offered to clients with ALPN on the DoT listener (RFC 7858 3.2).
+pub const DOT_ALPN_PROTOCOLS: [&[u8]; 1] = [b"dot"];
 
-        let question = query.questions.get(0).unwrap();
-        let domain = Domain::from(question.domain_name.to_string().as_str());
+/// The transport a query was received over, which decides how large the response may be.
+#[derive(Clone, Copy)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Https,
+}
 
-        let q_type = question.q_type.to_string();
-        let record_type: RecordType = q_type.parse().unwrap_or(RecordType::A);
+/// State shared between all the tasks spawned by the listeners.
+pub struct Context {
+    forwarder: Forwarder,
+    filter: Filter,
+    cache: Mutex<Cache>,
+    /// The questions being resolved upstream, with everyone waiting for the response
+    in_flight: Mutex<HashMap<dns::DnsQuestion, Vec<oneshot::Sender<Resolved>>>>,
+}
 
-        if let Some(entry) = filter::blacklist::find(&domain.name) {
-            let mut flags = query.flags.clone();
+impl Context {
+    pub fn new(forwarder: Forwarder, filter: Filter, cache: Cache) -> Context {
+        Context {
+            forwarder,
+            filter,
+            cache: Mutex::new(cache),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+}
 
-            info!("{}", entry.format_message(&domain));
+/// The outcome of resolving a question upstream, shared with every query that asked it.
+type Resolved = Result<Vec<u8>, Arc<ResolveError>>;
 
-            flags.rcode = RCode::Refused;
+/// Where the response to a query came from, for the logs.
+#[derive(Clone, Copy)]
+enum Origin {
+    Cache,
+    /// The same question was already being resolved upstream, and the query waited for it
+    Coalesced,
+    Upstream,
+}
 
-            let dns = Dns {
-                id: query.id,
-                flags,
-                questions: query.questions,
-                additionals: Vec::new(),
-                answers: Vec::new(),
-                authorities: Vec::new(),
+impl Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Origin::Cache => "cached",
+            Origin::Coalesced => "coalesced",
+            Origin::Upstream => "not cached",
+        })
+    }
+}
+
+/// Where the socket of a listener comes from.
+pub enum Source {
+    /// An address for us to bind to
+    Address(SocketAddr),
+    /// A socket that is already bound, passed to us by systemd (socket activation)
+    Inherited(Socket),
+}
+
+impl Source {
+    /// Returns a socket for every protocol of every listener.
+    pub fn from_config(listeners: &[ListenerConfig]) -> Vec<(Source, ListenProtocol)> {
+        listeners
+            .iter()
+            .flat_map(|listener| {
+                listener
+                    .protocols
+                    .iter()
+                    .map(|protocol| (Source::Address(listener.address), *protocol))
+            })
+            .collect()
+    }
+
+    /// Returns the sockets passed by systemd, along with a socket for every protocol of every
+    /// listener that systemd didn't pass one for. A listener is covered by a passed socket bound to
+    /// the same address, that's also either a datagram or a stream socket.
+    pub fn with_inherited(
+        inherited: Vec<PassedSocket>,
+        listeners: &[ListenerConfig],
+    ) -> Vec<(Source, ListenProtocol)> {
+        let covered: Vec<(SocketAddr, bool)> = inherited
+            .iter()
+            .filter_map(|(socket, protocol)| {
+                let address = socket.local_addr().ok()?.as_socket()?;
+
+                Some((address, protocol.is_stream()))
+            })
+            .collect();
+
+        let mut sockets: Vec<(Source, ListenProtocol)> = inherited
+            .into_iter()
+            .map(|(socket, protocol)| (Source::Inherited(socket), protocol))
+            .collect();
+
+        for (source, protocol) in Source::from_config(listeners) {
+            let Source::Address(address) = source else {
+                continue;
             };
 
-            let response = dns::encode(dns).unwrap();
+            if covered.contains(&(address, protocol.is_stream())) {
+                debug!(
+                    "listener on {} ({:?}) is served by a socket from systemd",
+                    address, protocol
+                );
 
-            socket.send_to(&response, src).unwrap();
+                continue;
+            }
 
-            continue;
+            sockets.push((source, protocol));
         }
 
-        let question = dns::DnsQuestion {
-            name: domain.name.clone(),
-            r#type: record_type.value(),
-        };
+        sockets
+    }
+}
 
-        let cached_response = cache.get(&question);
-        let was_cached = cached_response.is_some();
+impl Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Address(address) => write!(f, "{}", address),
+            Source::Inherited(socket) => match socket
+                .local_addr()
+                .ok()
+                .and_then(|address| address.as_socket())
+            {
+                Some(address) => write!(f, "{} (from systemd)", address),
+                None => f.write_str("a socket from systemd"),
+            },
+        }
+    }
+}
 
-        let start_time = Utc::now().time();
+/// A socket to serve clients on, with the protocol to serve over it.
+pub enum Listener {
+    Udp(Source),
+    Tcp(Source),
+    Dot(Source, Arc<tls::Acceptor>),
+    Doh(Source, Arc<tls::Acceptor>),
+}
 
-        let response = {
-            if was_cached {
-                let unwrapped = cached_response.unwrap();
+/// Pairs the sockets with what is needed to serve their protocol.
+///
+/// The certificate is only loaded if a listener serves over TLS, and is shared between all of them.
+pub fn listeners(
+    sockets: Vec<(Source, ListenProtocol)>,
+    config: &SwiftConfig,
+) -> Result<Vec<Listener>, Box<dyn Error>> {
+    let mut dot_acceptor = None;
+    let mut doh_acceptor = None;
 
-                unwrapped.response.clone()
-            } else {
-                dns::resolve(&client, &domain.name, &record_type)
-                    .await
-                    .unwrap()
+    let acceptor = |cached: &mut Option<Arc<tls::Acceptor>>, alpn: &[&[u8]]| {
+        if let Some(acceptor) = cached {
+            return Ok::<_, Box<dyn Error>>(acceptor.clone());
+        }
+
+        let acceptor = tls::Acceptor::new(config, alpn)?;
+        tokio::spawn(acceptor.clone().watch());
+
+        Ok(cached.insert(acceptor).clone())
+    };
+
+    let mut listeners = Vec::new();
+
+    for (source, protocol) in sockets {
+        listeners.push(match protocol {
+            ListenProtocol::Udp => Listener::Udp(source),
+            ListenProtocol::Tcp => Listener::Tcp(source),
+            ListenProtocol::Dot => {
+                Listener::Dot(source, acceptor(&mut dot_acceptor, &DOT_ALPN_PROTOCOLS)?)
+            }
+            ListenProtocol::Doh => {
+                Listener::Doh(source, acceptor(&mut doh_acceptor, &doh::ALPN_PROTOCOLS)?)
+            }
+        });
+    }
+
+    Ok(listeners)
+}
+
+/// Creates a socket bound to `address`. IPv6 sockets only take IPv6 traffic, so that `[::]` and
+/// `0.0.0.0` can be listened on side by side.
+fn bind(address: SocketAddr, r#type: Type) -> io::Result<Socket> {
+    let protocol = match r#type {
+        Type::DGRAM => Protocol::UDP,
+        _ => Protocol::TCP,
+    };
+
+    let socket = Socket::new(Domain::for_address(address), r#type, Some(protocol))?;
+
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    if r#type == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+
+    socket.bind(&address.into())?;
+
+    Ok(socket)
+}
+
+fn bind_udp(source: Source) -> io::Result<UdpSocket> {
+    let socket = match source {
+        Source::Address(address) => bind(address, Type::DGRAM)?,
+        Source::Inherited(socket) => socket,
+    };
+
+    socket.set_nonblocking(true)?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+fn bind_tcp(source: Source) -> io::Result<TcpListener> {
+    let socket = match source {
+        Source::Address(address) => {
+            let socket = bind(address, Type::STREAM)?;
+            socket.listen(1024)?;
+
+            socket
+        }
+        // systemd already listens on the socket
+        Source::Inherited(socket) => socket,
+    };
+
+    socket.set_nonblocking(true)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Binds every listener and serves clients on them, all sharing the same cache.
+pub async fn start(
+    listeners: Vec<Listener>,
+    forwarder: Forwarder,
+    filter: Filter,
+    cache: Cache,
+    snapshot: Option<Snapshot>,
+) {
+    let context = Arc::new(Context::new(forwarder, filter, cache));
+
+    tokio::spawn(sweep_cache(context.clone()));
+
+    if let Some(snapshot) = &snapshot {
+        tokio::spawn(save_cache(context.clone(), snapshot.clone()));
+    }
+
+    let mut tasks = Vec::new();
+
+    // Everything is bound before serving anything, so a listener that can't be bound stops us
+    // from starting at all instead of leaving us half running
+    for listener in listeners {
+        let context = context.clone();
+
+        let task = match listener {
+            Listener::Udp(source) => {
+                let address = source.to_string();
+
+                let socket = match bind_udp(source) {
+                    Ok(socket) => Arc::new(socket),
+                    Err(err) => panic!(
+                        "failed to bind udp listener on addr `{}` ({})",
+                        address, err
+                    ),
+                };
+
+                info!("listening on {address} (udp)");
+
+                tokio::spawn(serve_udp(socket, context))
+            }
+            Listener::Tcp(source) => {
+                let address = source.to_string();
+
+                let listener = match bind_tcp(source) {
+                    Ok(listener) => listener,
+                    Err(err) => panic!(
+                        "failed to bind tcp listener on addr `{}` ({})",
+                        address, err
+                    ),
+                };
+
+                info!("listening on {address} (tcp)");
+
+                tokio::spawn(serve_tcp(listener, context))
+            }
+            Listener::Dot(source, acceptor) => {
+                let address = source.to_string();
+
+                let listener = match bind_tcp(source) {
+                    Ok(listener) => listener,
+                    Err(err) => panic!(
+                        "failed to bind dot listener on addr `{}` ({})",
+                        address, err
+                    ),
+                };
+
+                info!("listening on {address} (dot)");
+
+                tokio::spawn(serve_tls(listener, acceptor, context))
+            }
+            Listener::Doh(source, acceptor) => {
+                let address = source.to_string();
+
+                let listener = match bind_tcp(source) {
+                    Ok(listener) => listener,
+                    Err(err) => panic!(
+                        "failed to bind doh listener on addr `{}` ({})",
+                        address, err
+                    ),
+                };
+
+                info!("listening on {address} (doh)");
+
+                tokio::spawn(doh::serve(listener, acceptor, context))
             }
         };
 
-        let end_time = Utc::now().time();
-        let total_time = end_time - start_time;
+        tasks.push(task);
+    }
+
+    tokio::select! {
+        _ = future::join_all(tasks) => {}
+        result = shutdown::requested() => match result {
+            Ok(()) => info!("shutting down"),
+            Err(err) => {
+                warn!("failed to handle shutdown signals ({})", err);
+
+                future::pending::<()>().await;
+            }
+        },
+    }
+
+    if let Some(snapshot) = &snapshot {
+        snapshot.save(&context.cache).await;
+    }
+}
+
+/// Saves the cache every so often, so it isn't lost entirely when we don't get to shut down cleanly.
+async fn save_cache(context: Arc<Context>, snapshot: Snapshot) {
+    let mut interval = time::interval(snapshot.interval);
 
-        if !was_cached && response.answer.is_some() {
-            cache.set(question, &response);
+    // The first tick completes right away, when there's nothing worth saving yet
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        snapshot.save(&context.cache).await;
+    }
+}
+
+/// Removes expired responses from the cache every so often, so that entries that are never asked
+/// for again don't linger until they are evicted.
+async fn sweep_cache(context: Arc<Context>) {
+    let mut interval = time::interval(CACHE_SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let mut cache = context.cache.lock().unwrap();
+        let removed = cache.remove_expired();
+
+        if removed > 0 {
+            debug!(
+                "removed {} expired responses from the cache ({} left)",
+                removed,
+                cache.len()
+            );
         }
+    }
+}
 
-        if let Some(answers) = response.answer {
-            query.answers = dns::format_answers(&answers);
+async fn serve_udp(socket: Arc<UdpSocket>, context: Arc<Context>) {
+    loop {
+        let mut buf = [0; dns::EDNS_PAYLOAD_SIZE as usize];
 
-            let encoding_result = dns::encode(query);
+        let (amt, src) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                warn!("failed to receive udp packet ({})", err);
 
-            if let Ok(encoded) = encoding_result {
-                socket.send_to(&encoded, src).unwrap();
+                continue;
+            }
+        };
 
-                info!(
-                    "successfully resolved `{}` record for `{}` ({}, {}ms)",
-                    record_type.to_string(),
-                    &domain.name,
-                    {
-                        if was_cached {
-                            "cached"
-                        } else {
-                            "not cached"
-                        }
-                    },
-                    total_time.num_milliseconds()
-                );
-            } else {
-                warn!(
-                    "notice: silently ignoring resolution of `{}` record for `{}`",
-                    record_type.to_string(),
-                    &domain.name
-                );
-                debug!("something went wrong when encoding: {:?}", encoding_result);
+        let socket = socket.clone();
+        let context = context.clone();
+
+        // Every query is handled in its own task, so that a slow upstream lookup
+        // doesn't hold up cache hits and blacklisted domains for other clients.
+        tokio::spawn(async move {
+            if let Some(response) = handle_packet(&context, &buf[..amt], Transport::Udp).await {
+                if let Err(err) = socket.send_to(&response, src).await {
+                    warn!("failed to send response to {} ({})", src, err);
+                }
+            }
+        });
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, context: Arc<Context>) {
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!("failed to accept tcp connection ({})", err);
+
+                continue;
+            }
+        };
+
+        let context = context.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = serve_tcp_connection(stream, &context).await {
+                debug!("tcp connection with {} closed ({})", src, err);
+            }
+        });
+    }
+}
+
+/// Serves DNS over TLS (RFC 7858), which is the same as over TCP once the handshake is done.
+async fn serve_tls(listener: TcpListener, acceptor: Arc<tls::Acceptor>, context: Arc<Context>) {
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!("failed to accept dot connection ({})", err);
+
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.get();
+        let context = context.clone();
+
+        tokio::spawn(async move {
+            // The handshake counts towards the idle timeout, so it can't hold the connection open
+            let stream = match time::timeout(TCP_IDLE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    debug!("tls handshake with {} failed ({})", src, err);
+
+                    return;
+                }
+                Err(_) => return,
+            };
+
+            if let Err(err) = serve_tcp_connection(stream, &context).await {
+                debug!("dot connection with {} closed ({})", src, err);
             }
-        } else {
-            let mut flags = query.flags.clone();
+        });
+    }
+}
 
-            flags.rcode = RCode::NXDomain;
+/// Serves queries on a single TCP (or TLS) connection until the client closes it or it goes idle.
+///
+/// Every message is prefixed with its length as a two-byte, big-endian integer (RFC 1035 4.2.2).
+/// Queries are answered concurrently, and every response is sent as soon as it's ready, so a slow
+/// one doesn't hold up the rest (RFC 7766 6.2.1.1).
+async fn serve_tcp_connection<S>(stream: S, context: &Arc<Context>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut reader, mut writer) = io::split(stream);
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+    let pending = Arc::new(Semaphore::new(TCP_MAX_PENDING_QUERIES));
 
-            let dns = Dns {
-                id: query.id,
-                flags,
-                questions: query.questions,
-                additionals: Vec::new(),
-                answers: Vec::new(),
-                authorities: Vec::new(),
+    let reading = async move {
+        loop {
+            // The whole message has to arrive in time, so a client can't hold the connection open
+            // by sending just part of it
+            let buf = match time::timeout(TCP_IDLE_TIMEOUT, read_message(&mut reader)).await {
+                Ok(Ok(Some(buf))) => buf,
+                Ok(Ok(None)) | Err(_) => return Ok(()),
+                Ok(Err(err)) => return Err(err),
             };
 
-            let encoded = dns::encode(dns).unwrap();
+            let permit = pending.clone().acquire_owned().await.unwrap();
+            let context = context.clone();
+            let sender = sender.clone();
+
+            tokio::spawn(async move {
+                if let Some(response) = handle_packet(&context, &buf, Transport::Tcp).await {
+                    // The length and message are written at once, so they end up in the same
+                    // segment (or TLS record) instead of two (RFC 7766 8)
+                    let mut message = Vec::with_capacity(response.len() + 2);
+                    message.extend_from_slice(&(response.len() as u16).to_be_bytes());
+                    message.extend_from_slice(&response);
 
-            socket.send_to(&encoded, src).unwrap();
+                    // The connection may have been closed in the meantime
+                    let _ = sender.send(message);
+                }
+
+                drop(permit);
+            });
+        }
+    };
+
+    // Runs until every query has been answered, after the client stopped sending them
+    let writing = async move {
+        while let Some(message) = receiver.recv().await {
+            writer.write_all(&message).await?;
+            writer.flush().await?;
+        }
+
+        Ok(())
+    };
+
+    tokio::try_join!(reading, writing).map(|_| ())
+}
+
+/// Reads the next length-prefixed message, or `None` if the client closed the connection in
+/// between two messages.
+async fn read_message<S>(stream: &mut S) -> io::Result<Option<Vec<u8>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let length = match stream.read_u16().await {
+        Ok(length) => length,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let mut buf = vec![0; length.into()];
+    stream.read_exact(&mut buf).await?;
+
+    Ok(Some(buf))
+}
+
+/// Reads and answers a single message, returning the encoded response (if there should be one).
+///
+/// Only the header, the question and the OPT record are read, the query is otherwise passed on as
+/// it is. Anything we don't understand (EDNS options, flags, record types) is left to the upstream.
+pub async fn handle_packet(
+    context: &Arc<Context>,
+    packet: &[u8],
+    transport: Transport,
+) -> Option<Vec<u8>> {
+    // Responses sent to us by mistake are dropped, replying to them could cause a loop
+    if wire::is_response(packet).ok()? {
+        return None;
+    }
+
+    let question = match wire::question(packet) {
+        Ok(question) => question,
+        Err(err) => {
+            debug!("received a malformed query ({})", err);
+
+            return wire::format_error(packet);
+        }
+    };
 
+    // An OPT record we can't read is treated like a missing one, the question is what matters
+    let opt = wire::opt(packet).unwrap_or_else(|err| {
+        debug!("ignoring the malformed OPT record of a query ({})", err);
+
+        None
+    });
+
+    let max_size = match transport {
+        Transport::Udp => dns::max_udp_payload(opt.as_ref()),
+        Transport::Tcp | Transport::Https => u16::MAX.into(),
+    };
+
+    // Our OPT record goes in the additional section of every response, but only if the client sent one
+    let mut edns = opt.map(|opt| wire::Edns {
+        payload_size: dns::EDNS_PAYLOAD_SIZE,
+        dnssec_ok: opt.dnssec_ok,
+        extended_rcode: 0,
+    });
+
+    let response = match opt {
+        // We only speak EDNS version 0, anything newer has to be answered with BADVERS (RFC 6891 6.1.3)
+        Some(opt) if opt.version > 0 => {
+            debug!("client sent unsupported EDNS version {}", opt.version);
+
+            if let Some(edns) = &mut edns {
+                edns.extended_rcode = 1;
+            }
+
+            empty_response(packet, wire::RCODE_NO_ERROR)?
+        }
+        _ => {
+            let dnssec_ok = opt.is_some_and(|opt| opt.dnssec_ok);
+
+            handle_query(context, packet, &question, dnssec_ok).await?
+        }
+    };
+
+    match wire::finalize(&response, packet, edns, max_size) {
+        Ok(response) => Some(response),
+        Err(err) => {
+            warn!(
+                "notice: silently ignoring a response that could not be relayed ({})",
+                err
+            );
+
+            None
+        }
+    }
+}
+
+/// Builds a response without any records, for queries that are refused or can't be answered.
+fn empty_response(query: &[u8], rcode: u8) -> Option<Vec<u8>> {
+    match wire::empty_response(query, rcode) {
+        Ok(response) => Some(response),
+        Err(err) => {
+            warn!("notice: silently ignoring a response that could not be encoded");
+            debug!("something went wrong when encoding: {}", err);
+
+            None
+        }
+    }
+}
+
+/// Answers a query, from the cache if possible. The response is returned in wire format.
+async fn handle_query(
+    context: &Arc<Context>,
+    query: &[u8],
+    question: &wire::Question,
+    dnssec_ok: bool,
+) -> Option<Vec<u8>> {
+    let domain = domain::Domain::from(question.name.as_str());
+    let q_type = dns::type_name(question.r#type);
+
+    if let Some(entry) = context.filter.find(&domain.name) {
+        info!("{}", entry.format_message(&domain));
+
+        return empty_response(query, wire::RCODE_REFUSED);
+    }
+
+    let cache_key = dns::DnsQuestion {
+        name: domain.name.clone(),
+        r#type: question.r#type,
+        class: question.class,
+        dnssec_ok,
+    };
+
+    // The lock is only held for the lookup itself, never across the upstream request.
+    let cached_response = context.cache.lock().unwrap().get(&cache_key);
+    let was_cached = cached_response.is_some();
+
+    let stale = match cached_response {
+        Some(_) => None,
+        None => context.cache.lock().unwrap().get_stale(&cache_key),
+    };
+
+    // A question that recently failed to resolve is answered with the stale response right away,
+    // and only resolved again in the background every so often
+    if let Some(retry_at) = stale.as_ref().and_then(|stale| stale.retry_at) {
+        if retry_at <= Utc::now() {
+            context.cache.lock().unwrap().postpone_retry(&cache_key);
+
+            tokio::spawn(refresh(
+                context.clone(),
+                question.clone(),
+                cache_key.clone(),
+            ));
+        }
+
+        return serve_stale(context, &cache_key, stale);
+    }
+
+    // Popular entries are refreshed before they expire, so clients don't have to wait for them
+    if was_cached && context.cache.lock().unwrap().needs_prefetch(&cache_key) {
+        debug!("prefetching `{}` record for `{}`", q_type, domain.name);
+
+        tokio::spawn(refresh(
+            context.clone(),
+            question.clone(),
+            cache_key.clone(),
+        ));
+    }
+
+    let start_time = Utc::now().time();
+
+    let (response, origin) = if let Some(cached) = cached_response {
+        (Ok(cached.response), Origin::Cache)
+    } else if stale.is_some() {
+        // The question is resolved in a task of its own, which still caches the response if we
+        // stop waiting for it
+        match time::timeout(STALE_ANSWER_TIMEOUT, resolve(context, question, &cache_key)).await {
+            Ok(resolved) => resolved,
+            Err(_) => {
+                debug!(
+                    "upstreams are taking too long to resolve `{}` record for `{}`",
+                    q_type, domain.name
+                );
+
+                return serve_stale(context, &cache_key, stale);
+            }
+        }
+    } else {
+        resolve(context, question, &cache_key).await
+    };
+
+    let end_time = Utc::now().time();
+    let total_time = end_time - start_time;
+
+    let response = match response {
+        Ok(response)
+            if stale.is_some()
+                && wire::rcode(&response)
+                    .is_ok_and(|rcode| rcode == wire::RCODE_SERVER_FAILURE) =>
+        {
+            warn!(
+                "upstream failed to resolve `{}` record for `{}`",
+                q_type, domain.name
+            );
+
+            return serve_stale(context, &cache_key, stale);
+        }
+        Ok(response) => response,
+        Err(err) if matches!(*err, ResolveError::Unsupported(_)) => {
             info!(
-                "no `{}` record exists for {}",
-                record_type.to_string(),
-                domain.name
+                "refusing to resolve unsupported `{}` record for `{}`",
+                q_type, domain.name
             );
+
+            return empty_response(query, wire::RCODE_NOT_IMPLEMENTED);
         }
+        Err(err) => {
+            warn!(
+                "failed to resolve `{}` record for `{}` ({})",
+                q_type, domain.name, err
+            );
+
+            if stale.is_some() {
+                return serve_stale(context, &cache_key, stale);
+            }
+
+            return empty_response(query, wire::RCODE_SERVER_FAILURE);
+        }
+    };
+
+    let has_answers = wire::answer_count(&response).is_ok_and(|count| count > 0);
+
+    if has_answers {
+        info!(
+            "successfully resolved `{}` record for `{}` ({}, {}ms)",
+            q_type,
+            &domain.name,
+            origin,
+            total_time.num_milliseconds()
+        );
+    } else if wire::rcode(&response).is_ok_and(|rcode| rcode == wire::RCODE_NX_DOMAIN) {
+        info!("`{}` does not exist ({})", domain.name, origin);
+    } else {
+        info!(
+            "no `{}` record exists for {} ({})",
+            q_type, domain.name, origin
+        );
+    }
+
+    Some(response)
+}
+
+/// Answers with an expired response after resolving failed or took too long (RFC 8767), and holds
+/// off on resolving the question again for a bit.
+fn serve_stale(
+    context: &Context,
+    cache_key: &dns::DnsQuestion,
+    stale: Option<CacheEntry>,
+) -> Option<Vec<u8>> {
+    let stale = stale?;
+
+    if stale.retry_at.is_none() {
+        context.cache.lock().unwrap().postpone_retry(cache_key);
+    }
+
+    info!("serving stale response for `{}`", cache_key.name);
+
+    Some(stale.response)
+}
+
+/// Resolves a question again in the background, and caches the response if that succeeds. Used to
+/// retry stale entries and to prefetch hot ones.
+async fn refresh(context: Arc<Context>, question: wire::Question, cache_key: dns::DnsQuestion) {
+    match resolve(&context, &question, &cache_key).await.0 {
+        Ok(_) => debug!("refreshed the cached response for `{}`", cache_key.name),
+        Err(err) => debug!(
+            "failed to refresh the cached response for `{}` ({})",
+            cache_key.name, err
+        ),
+    }
+}
+
+/// Resolves a question upstream and caches the response. If the same question is already being
+/// resolved, this waits for that response instead of sending another request.
+async fn resolve(
+    context: &Arc<Context>,
+    question: &wire::Question,
+    cache_key: &dns::DnsQuestion,
+) -> (Resolved, Origin) {
+    let (sender, receiver) = oneshot::channel();
+
+    let origin = match context.in_flight.lock().unwrap().entry(cache_key.clone()) {
+        Entry::Occupied(mut waiting) => {
+            debug!(
+                "waiting for the response to `{}` already in flight",
+                cache_key.name
+            );
+
+            waiting.get_mut().push(sender);
+
+            Origin::Coalesced
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(vec![sender]);
+
+            // Resolved in a task of its own, so it finishes even if the query that started it is
+            // dropped (e.g. when its client disconnects)
+            tokio::spawn(resolve_in_flight(
+                context.clone(),
+                question.clone(),
+                cache_key.clone(),
+            ));
+
+            Origin::Upstream
+        }
+    };
+
+    match receiver.await {
+        Ok(resolved) => (resolved, origin),
+        // The task resolving it went away without a response (e.g. it panicked)
+        Err(_) => {
+            debug!(
+                "the response to `{}` in flight was lost, resolving it again",
+                cache_key.name
+            );
+
+            (
+                resolve_upstream(context, question, cache_key).await,
+                Origin::Upstream,
+            )
+        }
+    }
+}
+
+/// Resolves a question upstream and caches the response, without regard for the same question
+/// being in flight.
+async fn resolve_upstream(
+    context: &Context,
+    question: &wire::Question,
+    cache_key: &dns::DnsQuestion,
+) -> Resolved {
+    let mut resolved = context
+        .forwarder
+        .resolve(question, cache_key.dnssec_ok)
+        .await
+        .map_err(Arc::new);
+
+    if let Ok(response) = &mut resolved {
+        context
+            .cache
+            .lock()
+            .unwrap()
+            .set(cache_key.clone(), response);
+    }
+
+    resolved
+}
+
+/// Resolves a question for everyone waiting on it in [`Context::in_flight`].
+async fn resolve_in_flight(
+    context: Arc<Context>,
+    question: wire::Question,
+    cache_key: dns::DnsQuestion,
+) {
+    let in_flight = InFlight {
+        context: context.clone(),
+        cache_key: Some(cache_key.clone()),
+    };
+
+    let resolved = resolve_upstream(&context, &question, &cache_key).await;
+
+    for sender in in_flight.finish() {
+        // The query may have been dropped in the meantime
+        let _ = sender.send(resolved.clone());
+    }
+}
+
+/// A question in [`Context::in_flight`], which is removed again when this is dropped. If that
+/// happens before there's a response, everyone waiting on it finds out and resolves it themselves.
+struct InFlight {
+    context: Arc<Context>,
+    /// Taken once the question is removed, so that the same question asked again in the meantime
+    /// isn't removed as well
+    cache_key: Option<dns::DnsQuestion>,
+}
+
+impl InFlight {
+    /// Removes the question, and returns everyone waiting for its response.
+    fn finish(mut self) -> Vec<oneshot::Sender<Resolved>> {
+        self.remove()
+    }
+
+    fn remove(&mut self) -> Vec<oneshot::Sender<Resolved>> {
+        let Some(cache_key) = self.cache_key.take() else {
+            return Vec::new();
+        };
+
+        self.context
+            .in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&cache_key)
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{self, File},
+        io::ErrorKind,
+        net::SocketAddr,
+        process,
+        sync::Arc,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use chrono::Utc;
+    use dns_message_parser::RCode;
+    use futures_util::future;
+    use rcgen::CertifiedKey;
+    use rustls::{pki_types::ServerName, ClientConfig};
+    use socket2::{Domain, Socket, Type};
+    use tokio::{
+        io::{self, AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        time::{self, Duration, Instant},
+    };
+    use tokio_rustls::{client::TlsStream, TlsConnector};
+
+    use super::{
+        handle_packet, read_message, resolve, serve_tcp_connection, serve_tls, InFlight, Origin,
+        Source, Transport, DOT_ALPN_PROTOCOLS, TCP_IDLE_TIMEOUT,
+    };
+    use crate::{
+        cache::{Cache, CacheEntry, STALE_TTL},
+        config::{ListenProtocol, ListenerConfig, SwiftConfig},
+        dns::DnsQuestion,
+        testing::{a, certificate, context, message, query, roots, SERVER_NAME},
+        tls, wire,
+    };
+
+    #[tokio::test]
+    async fn forwards_unknown_edns_options() {
+        let (context, received) = context(Duration::ZERO).await;
+
+        // NSID (RFC 5001) and edns-tcp-keepalive (RFC 7828), both empty in a query
+        let packet = query("example.com", 1, Some((0, &[0, 3, 0, 0, 0, 11, 0, 0])));
+        let response = handle_packet(&context, &packet, Transport::Udp)
+            .await
+            .unwrap();
+
+        assert_eq!(wire::id(&response), Ok(0x1234));
+        assert_eq!(wire::rcode(&response), Ok(wire::RCODE_NO_ERROR));
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn forwards_dnssec_ok_queries() {
+        let (context, received) = context(Duration::ZERO).await;
+
+        let packet = query("example.com", 1, Some((0x8000, &[])));
+        let response = handle_packet(&context, &packet, Transport::Udp)
+            .await
+            .unwrap();
+
+        assert_eq!(wire::rcode(&response), Ok(wire::RCODE_NO_ERROR));
+        assert!(wire::opt(&response).unwrap().unwrap().dnssec_ok);
+
+        let forwarded = received.lock().unwrap()[0].clone();
+
+        assert!(wire::opt(&forwarded).unwrap().unwrap().dnssec_ok);
+    }
+
+    #[tokio::test]
+    async fn forwards_unknown_types() {
+        let (context, received) = context(Duration::ZERO).await;
+
+        let packet = query("example.com", 65534, None);
+        let response = handle_packet(&context, &packet, Transport::Udp)
+            .await
+            .unwrap();
+
+        assert_eq!(wire::rcode(&response), Ok(wire::RCODE_NO_ERROR));
+
+        let forwarded = received.lock().unwrap()[0].clone();
+
+        assert_eq!(wire::question(&forwarded).unwrap().r#type, 65534);
+    }
+
+    #[tokio::test]
+    async fn caches_classes_apart() {
+        let (context, received) = context(Duration::ZERO).await;
+
+        let cache_key = DnsQuestion {
+            name: String::from("example.com"),
+            r#type: 1,
+            class: 1,
+            dnssec_ok: false,
+        };
+
+        let mut response = message(
+            "example.com",
+            RCode::NoError,
+            vec![a("example.com", 300)],
+            Vec::new(),
+        );
+
+        context.cache.lock().unwrap().set(cache_key, &mut response);
+
+        let packet = query("example.com", 1, None);
+        let response = handle_packet(&context, &packet, Transport::Udp)
+            .await
+            .unwrap();
+
+        assert_eq!(wire::answer_count(&response), Ok(1));
+        assert!(received.lock().unwrap().is_empty());
+
+        // The same name and type in the CHAOS class is another question
+        let mut packet = packet;
+        let length = packet.len();
+        packet[length - 1] = 3;
+
+        let response = handle_packet(&context, &packet, Transport::Udp)
+            .await
+            .unwrap();
+
+        assert_eq!(wire::answer_count(&response), Ok(0));
+        assert_eq!(wire::question(&response).unwrap().class, 3);
+
+        let forwarded = received.lock().unwrap()[0].clone();
+
+        assert_eq!(wire::question(&forwarded).unwrap().class, 3);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_queries() {
+        let (context, received) = context(Duration::ZERO).await;
+
+        let packet = query("example.com", 1, None);
+        let response = handle_packet(&context, &packet[..packet.len() - 1], Transport::Udp)
+            .await
+            .unwrap();
+
+        assert_eq!(wire::id(&response), Ok(0x1234));
+        assert_eq!(wire::rcode(&response), Ok(wire::RCODE_FORMAT_ERROR));
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn coalesces_identical_queries() {
+        let (context, received) = context(Duration::from_millis(200)).await;
+
+        let packet = query("example.com", 1, None);
+        let responses =
+            future::join_all((0..10).map(|_| handle_packet(&context, &packet, Transport::Udp)))
+                .await;
+
+        assert!(responses.iter().all(|response| response
+            .as_ref()
+            .is_some_and(|response| wire::rcode(response) == Ok(wire::RCODE_NO_ERROR))));
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert!(context.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn coalesces_bursts_one_after_another() {
+        let (context, received) = context(Duration::from_millis(100)).await;
+
+        let packet = query("example.com", 1, None);
+        let burst = || {
+            (0..10)
+                .map(|_| {
+                    let context = context.clone();
+                    let packet = packet.clone();
+
+                    tokio::spawn(
+                        async move { handle_packet(&context, &packet, Transport::Udp).await },
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut first = burst();
+
+        // The second burst starts while the rest of the first one is still being answered. The
+        // response has no records, so it isn't cached and has to be resolved again
+        first.remove(0).await.unwrap().unwrap();
+
+        let second = burst();
+
+        for response in future::join_all(first.into_iter().chain(second)).await {
+            assert!(response.unwrap().is_some());
+        }
+
+        assert_eq!(received.lock().unwrap().len(), 2);
+        assert!(context.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn clears_questions_in_flight_once() {
+        let (context, _) = context(Duration::ZERO).await;
+
+        let cache_key = DnsQuestion {
+            name: String::from("example.com"),
+            r#type: 1,
+            class: 1,
+            dnssec_ok: false,
+        };
+
+        let mut in_flight = context.in_flight.lock().unwrap();
+        in_flight.insert(cache_key.clone(), Vec::new());
+        drop(in_flight);
+
+        let mut guard = InFlight {
+            context: context.clone(),
+            cache_key: Some(cache_key.clone()),
+        };
+
+        guard.remove();
+
+        // Asked again after the first response was handed out, which the guard mustn't remove
+        context
+            .in_flight
+            .lock()
+            .unwrap()
+            .insert(cache_key.clone(), Vec::new());
+
+        drop(guard);
+
+        assert!(context.in_flight.lock().unwrap().contains_key(&cache_key));
+    }
+
+    #[tokio::test]
+    async fn resolves_alone_when_the_query_in_flight_is_lost() {
+        let (context, received) = context(Duration::ZERO).await;
+
+        let question = wire::Question::new("example.com", 1).unwrap();
+        let cache_key = DnsQuestion {
+            name: question.name.clone(),
+            r#type: 1,
+            class: 1,
+            dnssec_ok: false,
+        };
+
+        // Stands in for a query whose task went away before sending the response
+        context
+            .in_flight
+            .lock()
+            .unwrap()
+            .insert(cache_key.clone(), Vec::new());
+
+        let waiting = tokio::spawn({
+            let context = context.clone();
+            let cache_key = cache_key.clone();
+
+            async move { resolve(&context, &question, &cache_key).await }
+        });
+
+        while context.in_flight.lock().unwrap()[&cache_key].is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        context.in_flight.lock().unwrap().remove(&cache_key);
+
+        let (resolved, origin) = waiting.await.unwrap();
+
+        assert!(resolved.is_ok());
+        assert!(matches!(origin, Origin::Upstream));
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn serves_stale_responses_while_the_upstream_is_slow() {
+        let (context, received) = context(Duration::from_millis(2500)).await;
+
+        *context.cache.lock().unwrap() = Cache::new(&SwiftConfig {
+            serve_stale: 3600,
+            ..SwiftConfig::default()
+        });
+
+        let cache_key = DnsQuestion {
+            name: String::from("example.com"),
+            r#type: 1,
+            class: 1,
+            dnssec_ok: false,
+        };
+
+        let response = message(
+            "example.com",
+            RCode::NoError,
+            vec![a("example.com", 300)],
+            Vec::new(),
+        );
+
+        let now = Utc::now();
+        let expired = CacheEntry::new(
+            now - chrono::Duration::seconds(400),
+            now - chrono::Duration::seconds(100),
+            response,
+        );
+
+        context.cache.lock().unwrap().insert(cache_key, expired);
+
+        let start = Instant::now();
+        let packet = query("example.com", 1, None);
+        let response = handle_packet(&context, &packet, Transport::Udp)
+            .await
+            .unwrap();
+
+        assert!(start.elapsed() < Duration::from_millis(2500));
+        assert_eq!(wire::answer_count(&response), Ok(1));
+        assert_eq!(wire::min_answer_ttl(&response), Ok(Some(STALE_TTL)));
+
+        // The upstream still gets to answer
+        while !context.in_flight.lock().unwrap().is_empty() {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    /// Prefixes a query with its length, as it's sent over TCP.
+    fn framed(query: &[u8]) -> Vec<u8> {
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(query);
+
+        message
+    }
+
+    #[tokio::test]
+    async fn answers_queries_over_tcp_as_they_resolve() {
+        let (context, received) = context(Duration::from_millis(200)).await;
+
+        let cache_key = DnsQuestion {
+            name: String::from("cached.example"),
+            r#type: 1,
+            class: 1,
+            dnssec_ok: false,
+        };
+
+        let mut response = message(
+            "cached.example",
+            RCode::NoError,
+            vec![a("cached.example", 300)],
+            Vec::new(),
+        );
+
+        context.cache.lock().unwrap().set(cache_key, &mut response);
+
+        let (mut client, server) = io::duplex(4096);
+        let serving = tokio::spawn({
+            let context = context.clone();
+
+            async move { serve_tcp_connection(server, &context).await }
+        });
+
+        // Both queries arrive at once, the first one has to be resolved upstream
+        let mut slow = query("slow.example", 1, None);
+        let mut cached = query("cached.example", 1, None);
+        wire::set_id(&mut slow, 1).unwrap();
+        wire::set_id(&mut cached, 2).unwrap();
+
+        client
+            .write_all(&[framed(&slow), framed(&cached)].concat())
+            .await
+            .unwrap();
+
+        let first = read_message(&mut client).await.unwrap().unwrap();
+        let second = read_message(&mut client).await.unwrap().unwrap();
+
+        assert_eq!(wire::id(&first), Ok(2));
+        assert_eq!(wire::id(&second), Ok(1));
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        drop(client);
+
+        assert!(serving.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn drops_tcp_connections_closed_mid_message() {
+        let (context, received) = context(Duration::ZERO).await;
+        let (mut client, server) = io::duplex(4096);
+
+        let packet = framed(&query("example.com", 1, None));
+
+        client.write_all(&packet[..10]).await.unwrap();
+        drop(client);
+
+        let err = serve_tcp_connection(server, &context).await.unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_idle_tcp_connections() {
+        let (context, _) = context(Duration::ZERO).await;
+        let (mut client, server) = io::duplex(4096);
+
+        let serving = tokio::spawn({
+            let context = context.clone();
+
+            async move { serve_tcp_connection(server, &context).await }
+        });
+
+        // Sending only part of a message doesn't keep the connection open either
+        let start = Instant::now();
+        let packet = framed(&query("example.com", 1, None));
+
+        client.write_all(&packet[..10]).await.unwrap();
+
+        assert_eq!(client.read(&mut [0; 1]).await.unwrap(), 0);
+        assert!(start.elapsed() >= TCP_IDLE_TIMEOUT);
+        assert!(serving.await.unwrap().is_ok());
+    }
+
+    /// Writes the certificate and key where the configuration points, as if they were renewed at
+    /// `modified`.
+    fn install(certified: &CertifiedKey, config: &SwiftConfig, modified: SystemTime) {
+        let files = [
+            (&config.tls_certificate, certified.cert.pem()),
+            (&config.tls_key, certified.key_pair.serialize_pem()),
+        ];
+
+        for (path, contents) in files {
+            fs::write(path, contents).unwrap();
+            File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+    }
+
+    /// Connects to a DoT listener, trusting only the certificate.
+    async fn connect(
+        address: SocketAddr,
+        certified: &CertifiedKey,
+    ) -> io::Result<TlsStream<TcpStream>> {
+        let mut config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots(certified))
+                .with_no_client_auth();
+
+        config.alpn_protocols = DOT_ALPN_PROTOCOLS.map(<[u8]>::to_vec).to_vec();
+
+        TlsConnector::from(Arc::new(config))
+            .connect(
+                ServerName::try_from(SERVER_NAME).unwrap(),
+                TcpStream::connect(address).await?,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn serves_dot_with_the_latest_certificate() {
+        let (context, received) = context(Duration::ZERO).await;
+
+        let directory = env::temp_dir().join(format!("swiftdns-tls-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let config = SwiftConfig {
+            tls_certificate: directory.join("cert.pem"),
+            tls_key: directory.join("key.pem"),
+            ..SwiftConfig::default()
+        };
+
+        let first = certificate();
+        install(&first, &config, UNIX_EPOCH);
+
+        let acceptor = tls::Acceptor::new(&config, &DOT_ALPN_PROTOCOLS).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(serve_tls(listener, acceptor.clone(), context));
+
+        let mut stream = connect(address, &first).await.unwrap();
+
+        stream
+            .write_all(&framed(&query("example.com", 1, None)))
+            .await
+            .unwrap();
+
+        let response = read_message(&mut stream).await.unwrap().unwrap();
+
+        assert_eq!(wire::id(&response), Ok(0x1234));
+        assert_eq!(wire::rcode(&response), Ok(wire::RCODE_NO_ERROR));
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        let second = certificate();
+        install(&second, &config, SystemTime::now());
+        acceptor.reload();
+
+        let renewed = connect(address, &second).await.unwrap();
+        let presented = renewed.get_ref().1.peer_certificates().unwrap();
+
+        assert_eq!(presented[0], *second.cert.der());
+        assert!(connect(address, &first).await.is_err());
+
+        // Connections that were already open keep going with the previous certificate
+        stream
+            .write_all(&framed(&query("example.org", 1, None)))
+            .await
+            .unwrap();
+
+        assert!(read_message(&mut stream).await.unwrap().is_some());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn binds_listeners_systemd_didnt_pass() {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        socket
+            .bind(
+                &"127.0.0.1:0"
+                    .parse::<std::net::SocketAddr>()
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+
+        let passed = socket.local_addr().unwrap().as_socket().unwrap();
+        let other = "127.0.0.1:5353".parse().unwrap();
+
+        let listeners = [ListenerConfig::plain(passed), ListenerConfig::plain(other)];
+        let sockets = Source::with_inherited(vec![(socket, ListenProtocol::Udp)], &listeners);
+
+        let sockets: Vec<String> = sockets
+            .iter()
+            .map(|(source, protocol)| format!("{} {:?}", source, protocol))
+            .collect();
+
+        assert_eq!(
+            sockets,
+            [
+                format!("{} (from systemd) Udp", passed),
+                format!("{} Tcp", passed),
+                String::from("127.0.0.1:5353 Udp"),
+                String::from("127.0.0.1:5353 Tcp"),
+            ]
+        );
     }
 }
@@ -1,7 +1,7 @@
 use std::{
     env,
     error::Error,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
 };
 
@@ -23,6 +23,18 @@ impl Mode {
             Mode::Clean => String::from("1.1.1.3"),
         }
     }
+
+    /// The upstream to use when none are configured.
+    pub fn upstream(&self) -> UpstreamConfig {
+        UpstreamConfig {
+            name: None,
+            url: format!("https://{}/dns-query", self.ip_address()),
+            bootstrap: None,
+            sni: None,
+            pin: None,
+            protocol: None,
+        }
+    }
 }
 
 impl From<&str> for Mode {
@@ -39,20 +51,221 @@ impl From<&str> for Mode {
     }
 }
 
+/// How queries are sent to the upstream server.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[allow(clippy::enum_variant_names)]
+pub enum Protocol {
+    /// The JSON API (`application/dns-json`), which only supports the record types we can parse
+    DohJson,
+    /// RFC 8484, with the query in the body of a POST request
+    DohPost,
+    /// RFC 8484, with the query base64url-encoded in the URL of a GET request
+    DohGet,
+}
+
+/// A server to forward queries to.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpstreamConfig {
+    /// The name forwarding rules refer to the upstream by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The URL of the DoH endpoint (e.g. `https://dns.quad9.net/dns-query`), or the address of a
+    /// DoT server (e.g. `tls://9.9.9.9`) or plain DNS server (e.g. `udp://10.0.0.53:53`)
+    pub url: String,
+    /// The address to connect to, so the host name in `url` doesn't have to be resolved first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootstrap: Option<IpAddr>,
+    /// The name to send in the TLS handshake and verify the certificate against, instead of the host in `url`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+    /// The base64 encoded SHA-256 digest of the public key (SPKI) the DoT server's certificate must have
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin: Option<String>,
+    /// Overrides the global `protocol` for this upstream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Protocol>,
+}
+
+/// Sends queries for certain domains to a specific upstream.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ForwardConfig {
+    /// Patterns with the same syntax as the blacklist, e.g. `**.corp.example`
+    pub domains: Vec<String>,
+    /// The `name` of the upstream
+    pub upstream: String,
+}
+
+/// A protocol to serve clients over.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenProtocol {
+    Udp,
+    Tcp,
+    /// DNS over TLS (RFC 7858)
+    Dot,
+    /// DNS over HTTPS (RFC 8484)
+    Doh,
+}
+
+impl ListenProtocol {
+    /// Whether the protocol runs over TCP, only one of those can be served on an address.
+    pub fn is_stream(&self) -> bool {
+        !matches!(self, ListenProtocol::Udp)
+    }
+}
+
+/// An address to serve clients on.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    #[serde(default = "ListenerConfig::default_protocols")]
+    pub protocols: Vec<ListenProtocol>,
+}
+
+impl ListenerConfig {
+    fn default_protocols() -> Vec<ListenProtocol> {
+        vec![ListenProtocol::Udp, ListenProtocol::Tcp]
+    }
+
+    /// Makes sure the protocols can be served side by side on the same address.
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let streams = self
+            .protocols
+            .iter()
+            .filter(|protocol| protocol.is_stream());
+        let datagrams = self
+            .protocols
+            .iter()
+            .filter(|protocol| !protocol.is_stream());
+
+        if self.protocols.is_empty() {
+            return Err(format!("listener on `{}` has no protocols", self.address).into());
+        }
+
+        if streams.count() > 1 {
+            return Err(format!(
+                "listener on `{}` can only serve one of `tcp`, `dot` and `doh`",
+                self.address
+            )
+            .into());
+        }
+
+        if datagrams.count() > 1 {
+            return Err(
+                format!("listener on `{}` lists `udp` more than once", self.address).into(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// A listener for plain DNS over both UDP and TCP.
+    pub fn plain(address: SocketAddr) -> ListenerConfig {
+        ListenerConfig {
+            address,
+            protocols: ListenerConfig::default_protocols(),
+        }
+    }
+}
+
+/// How an upstream is picked for each query.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// The first upstream that is up, in the order they are listed in
+    Failover,
+    /// Every upstream in turn
+    RoundRobin,
+    /// The upstream with the lowest average response time
+    Fastest,
+    /// Several upstreams at once, taking whichever answers first
+    Race,
+}
+
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct SwiftConfig {
     pub mode: Mode,
-    pub address: SocketAddr,
+    /// Superseded by `listeners`, but still honored if there are none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<SocketAddr>,
+    /// The certificate chain and private key for serving over TLS, relative to the config directory
+    pub tls_certificate: PathBuf,
+    pub tls_key: PathBuf,
     pub tor: bool,
+    pub protocol: Protocol,
+    /// How long to wait for an upstream to respond (in milliseconds), before moving on to the next one
+    pub upstream_timeout: u64,
+    pub strategy: Strategy,
+    /// How many upstreams are queried at once with the `race` strategy
+    pub race_count: usize,
+    /// The most responses to cache, 0 for no limit
+    pub cache_max_entries: usize,
+    /// The most memory the cached responses may take up (in bytes), 0 for no limit
+    pub cache_max_bytes: usize,
+    /// The shortest and longest a response is cached for (in seconds), whatever the TTLs of its records
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+    /// The longest a response saying a name or record doesn't exist is cached for (in seconds)
+    pub negative_ttl: u32,
+    /// How long to keep responses after they expire (in seconds), to serve when no upstream can be reached, 0 to disable
+    pub serve_stale: u32,
+    /// Where to keep the cache across restarts, relative to the config directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_file: Option<PathBuf>,
+    /// How often the cache is written to `cache_file` while running (in seconds)
+    pub cache_save_interval: u64,
+    /// The servers to forward queries to, `mode` decides which of Cloudflare's resolvers is used if empty
+    pub upstreams: Vec<UpstreamConfig>,
+    pub forward: Vec<ForwardConfig>,
+    /// The addresses to serve clients on, 127.0.0.53:53 (over UDP and TCP) if empty
+    pub listeners: Vec<ListenerConfig>,
 }
 
 impl std::default::Default for SwiftConfig {
     fn default() -> Self {
         Self {
             mode: Mode::Standard,
-            address: "127.0.0.53:53".parse().unwrap(),
+            address: None,
+            tls_certificate: PathBuf::from("tls/cert.pem"),
+            tls_key: PathBuf::from("tls/key.pem"),
             tor: false,
+            protocol: Protocol::DohJson,
+            upstream_timeout: 3000,
+            strategy: Strategy::Failover,
+            race_count: 2,
+            cache_max_entries: 10_000,
+            cache_max_bytes: 16 * 1024 * 1024,
+            min_ttl: 0,
+            max_ttl: 86400,
+            negative_ttl: 3600,
+            serve_stale: 0,
+            cache_file: None,
+            cache_save_interval: 300,
+            upstreams: Vec::new(),
+            forward: Vec::new(),
+            listeners: Vec::new(),
+        }
+    }
+}
+
+impl SwiftConfig {
+    /// Returns the configured listeners, or one on the older `address` setting if there are none.
+    pub fn listeners(&self) -> Result<Vec<ListenerConfig>, Box<dyn Error>> {
+        if !self.listeners.is_empty() {
+            for listener in &self.listeners {
+                listener.validate()?;
+            }
+
+            return Ok(self.listeners.clone());
         }
+
+        let address = self
+            .address
+            .unwrap_or_else(|| "127.0.0.53:53".parse().unwrap());
+
+        Ok(vec![ListenerConfig::plain(address)])
     }
 }
 
@@ -70,3 +283,40 @@ pub fn config_location() -> PathBuf {
         Path::new("/etc/swiftdns/").to_path_buf()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ListenProtocol, ListenerConfig, SwiftConfig};
+
+    #[test]
+    fn falls_back_to_the_older_listen_address() {
+        let mut config = SwiftConfig::default();
+
+        let listeners = config.listeners().unwrap();
+
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].address, "127.0.0.53:53".parse().unwrap());
+        assert_eq!(
+            listeners[0].protocols,
+            [ListenProtocol::Udp, ListenProtocol::Tcp]
+        );
+
+        config.address = Some("127.0.0.1:53".parse().unwrap());
+
+        let listeners = config.listeners().unwrap();
+
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].address, "127.0.0.1:53".parse().unwrap());
+
+        config.listeners = vec![ListenerConfig::plain("[::1]:53".parse().unwrap())];
+
+        let listeners = config.listeners().unwrap();
+
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].address, "[::1]:53".parse().unwrap());
+
+        config.listeners[0].protocols.push(ListenProtocol::Doh);
+
+        assert!(config.listeners().is_err());
+    }
+}
@@ -1,23 +1,53 @@
 use std::{
     error::Error,
-    fmt::Display,
-    net::{Ipv4Addr, Ipv6Addr},
+    fmt::{self, Display},
+    io,
     str::FromStr,
 };
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
 use dns_message_parser::{
-    rr::{self, RR},
-    DecodeError, Dns, DomainName, Flags,
+    question::{QClass, QType, Question},
+    rr::RR,
+    DecodeError, Dns, DomainName, Flags, Opcode, RCode,
 };
 use strum::{EnumIter, IntoEnumIterator};
 
-use crate::config;
+use crate::{
+    config::Protocol,
+    domain::Domain,
+    record,
+    wire::{self, WireError},
+};
+
+/// The UDP payload size we advertise in our own OPT record, and the most we will send over UDP.
+///
+/// This is the value agreed upon for DNS flag day 2020, which avoids IP fragmentation on most networks.
+pub const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+/// The media type of DNS messages in wire format (RFC 8484 6).
+pub const DNS_MESSAGE: &str = "application/dns-message";
+
+/// The payload size that can always be sent over UDP, used when the client doesn't support EDNS.
+pub const DEFAULT_PAYLOAD_SIZE: u16 = 512;
 
 #[derive(Debug, EnumIter, Clone, Eq, Hash, PartialEq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum RecordType {
     A,
     AAAA,
+    CNAME,
+    MX,
+    TXT,
+    NS,
+    SOA,
+    PTR,
+    SRV,
+    CAA,
+    HTTPS,
+    SVCB,
+    DS,
+    DNSKEY,
 }
 
 impl RecordType {
@@ -25,8 +55,24 @@ impl RecordType {
         match self {
             RecordType::A => 1,
             RecordType::AAAA => 28,
+            RecordType::CNAME => 5,
+            RecordType::MX => 15,
+            RecordType::TXT => 16,
+            RecordType::NS => 2,
+            RecordType::SOA => 6,
+            RecordType::PTR => 12,
+            RecordType::SRV => 33,
+            RecordType::CAA => 257,
+            RecordType::HTTPS => 65,
+            RecordType::SVCB => 64,
+            RecordType::DS => 43,
+            RecordType::DNSKEY => 48,
         }
     }
+
+    pub fn from_value(value: u16) -> Option<RecordType> {
+        RecordType::iter().find(|record_type| record_type.value() == value)
+    }
 }
 
 impl Display for RecordType {
@@ -34,12 +80,33 @@ impl Display for RecordType {
         let str = match self {
             RecordType::A => "A",
             RecordType::AAAA => "AAAA",
+            RecordType::CNAME => "CNAME",
+            RecordType::MX => "MX",
+            RecordType::TXT => "TXT",
+            RecordType::NS => "NS",
+            RecordType::SOA => "SOA",
+            RecordType::PTR => "PTR",
+            RecordType::SRV => "SRV",
+            RecordType::CAA => "CAA",
+            RecordType::HTTPS => "HTTPS",
+            RecordType::SVCB => "SVCB",
+            RecordType::DS => "DS",
+            RecordType::DNSKEY => "DNSKEY",
         };
 
         f.write_str(str)
     }
 }
 
+/// Returns the name of a record type, or its number in the generic notation from RFC 3597 5 for
+/// the types we don't know about (e.g. `TYPE65534`).
+pub fn type_name(r#type: u16) -> String {
+    match RecordType::from_value(r#type) {
+        Some(record_type) => record_type.to_string(),
+        None => format!("TYPE{}", r#type),
+    }
+}
+
 impl FromStr for RecordType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         for record_type in RecordType::iter() {
@@ -66,6 +133,71 @@ impl From<&str> for RecordType {
     }
 }
 
+/// Everything that can go wrong when resolving a query upstream.
+#[derive(Debug)]
+pub enum ResolveError {
+    /// The request could not be sent, or the upstream server didn't respond
+    Request(reqwest::Error),
+    /// The connection to a plain DNS upstream failed
+    Io(io::Error),
+    /// The upstream server didn't respond in time
+    Timeout(std::time::Duration),
+    /// The upstream server responded with an unexpected HTTP status
+    Status(reqwest::StatusCode),
+    /// The upstream server sent a response that isn't a valid DNS message
+    InvalidResponse(String),
+    /// The upstream server answered with SERVFAIL or REFUSED, the response is relayed if no other
+    /// upstream does better
+    Failure(Vec<u8>),
+    /// The record type can't be resolved with the configured protocol
+    Unsupported(u16),
+}
+
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::Request(err) => write!(f, "upstream request failed: {}", err),
+            ResolveError::Io(err) => write!(f, "upstream connection failed: {}", err),
+            ResolveError::Timeout(timeout) => {
+                write!(
+                    f,
+                    "upstream didn't respond within {}ms",
+                    timeout.as_millis()
+                )
+            }
+            ResolveError::Status(status) => write!(f, "upstream responded with status {}", status),
+            ResolveError::InvalidResponse(err) => {
+                write!(f, "upstream sent an invalid response: {}", err)
+            }
+            ResolveError::Failure(response) => match wire::rcode(response) {
+                Ok(wire::RCODE_REFUSED) => f.write_str("upstream refused the query"),
+                _ => f.write_str("upstream failed to resolve the query"),
+            },
+            ResolveError::Unsupported(r#type) => {
+                write!(
+                    f,
+                    "`{}` records can't be resolved with this protocol",
+                    type_name(*r#type)
+                )
+            }
+        }
+    }
+}
+
+impl Error for ResolveError {}
+
+impl From<io::Error> for ResolveError {
+    fn from(err: io::Error) -> Self {
+        ResolveError::Io(err)
+    }
+}
+
+impl From<reqwest::Error> for ResolveError {
+    fn from(err: reqwest::Error) -> Self {
+        ResolveError::Request(err)
+    }
+}
+
 #[derive(crate::Deserialize, Debug, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct DnsResponse {
@@ -80,7 +212,6 @@ pub struct DnsResponse {
     pub ad: bool,
     #[serde(rename = "CD")]
     pub cd: bool,
-    pub question: Option<Vec<DnsQuestion>>,
     pub answer: Option<Vec<DnsAnswer>>,
     pub authority: Option<Vec<DnsAnswer>>,
 }
@@ -95,10 +226,13 @@ pub struct DnsAnswer {
     pub data: String,
 }
 
-#[derive(crate::Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
+/// Identifies a query for caching, responses with and without DNSSEC records are kept apart.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct DnsQuestion {
     pub name: String,
     pub r#type: u16,
+    pub class: u16,
+    pub dnssec_ok: bool,
 }
 
 pub fn decode(query_bytes: &[u8]) -> Result<Dns, DecodeError> {
@@ -107,81 +241,216 @@ pub fn decode(query_bytes: &[u8]) -> Result<Dns, DecodeError> {
     Dns::decode(bytes.into())
 }
 
+/// The largest response that may be sent to the client over UDP.
+///
+/// Clients without EDNS support are limited to 512 bytes, and we never go above our own advertised size.
+pub fn max_udp_payload(opt: Option<&wire::Opt>) -> usize {
+    let size = match opt {
+        Some(opt) => opt
+            .payload_size
+            .clamp(DEFAULT_PAYLOAD_SIZE, EDNS_PAYLOAD_SIZE),
+        None => DEFAULT_PAYLOAD_SIZE,
+    };
+
+    size.into()
+}
+
+/// Converts the records from the JSON API. Records we don't support (such as RRSIG) or can't parse
+/// are left out of the response, so the rest of the answer can still be used.
 pub fn format_answers(answers: &Vec<DnsAnswer>) -> Vec<RR> {
     let mut group = Vec::new();
 
     for answer in answers {
-        if answer.r#type == RecordType::A.value() {
-            group.push(RR::A(rr::A {
-                domain_name: answer.domain_name.parse::<DomainName>().unwrap(),
-                ttl: answer.ttl,
-                ipv4_addr: answer.data.parse::<Ipv4Addr>().unwrap(),
-            }));
-        } else if answer.r#type == RecordType::AAAA.value() {
-            group.push(RR::AAAA(rr::AAAA {
-                domain_name: answer.domain_name.parse::<DomainName>().unwrap(),
-                ttl: answer.ttl,
-                ipv6_addr: answer.data.parse::<Ipv6Addr>().unwrap(),
-            }));
+        let record = RecordType::from_value(answer.r#type)
+            .and_then(|record_type| record::parse(&record_type, answer));
+
+        match record {
+            Some(record) => group.push(record),
+            None => debug!(
+                "leaving out `{}` record `{}` for `{}`",
+                type_name(answer.r#type),
+                answer.data,
+                answer.domain_name
+            ),
         }
     }
 
     group
 }
 
-pub fn encode(query: Dns) -> Result<bytes::BytesMut, ()> {
-    let dns = Dns::encode(&Dns {
-        id: query.id,
+/// Resolves the question with the JSON API, and builds a DNS message from the response.
+pub async fn resolve_json(
+    client: &reqwest::Client,
+    url: &reqwest::Url,
+    question: &wire::Question,
+    dnssec_ok: bool,
+) -> Result<Vec<u8>, ResolveError> {
+    // The API has no way to ask for other classes, or types it doesn't know by name
+    let record_type = RecordType::from_value(question.r#type)
+        .filter(|_| question.class == wire::CLASS_IN)
+        .ok_or(ResolveError::Unsupported(question.r#type))?;
+
+    let domain = Domain::from(question.name.as_str());
+
+    let echoed = Question {
+        domain_name: format!("{}.", question.name)
+            .parse::<DomainName>()
+            .map_err(|err| ResolveError::InvalidResponse(err.to_string()))?,
+        q_class: QClass::IN,
+        q_type: QType::try_from(question.r#type)
+            .map_err(|_| ResolveError::Unsupported(question.r#type))?,
+    };
+
+    let mut request = client
+        .get(url.clone())
+        .query(&[("name", domain.name), ("type", record_type.to_string())]);
+
+    // Only ask for DNSSEC records if the client asked for them, there's no point in
+    // fetching signatures a client will never look at.
+    if dnssec_ok {
+        request = request.query(&[("do", "1")]);
+    }
+
+    let res = request
+        .header(reqwest::header::ACCEPT, "application/dns-json")
+        .send()
+        .await?;
+
+    let status = res.status();
+
+    if !status.is_success() {
+        return Err(ResolveError::Status(status));
+    }
+
+    let response = res.json::<DnsResponse>().await?;
+
+    let rcode = RCode::try_from(response.status)
+        .map_err(|status| ResolveError::InvalidResponse(format!("unknown status {}", status)))?;
+
+    let dns = Dns {
+        id: 0,
         flags: Flags {
             qr: true,
-            opcode: query.flags.opcode,
-            aa: true,
-            tc: query.flags.tc,
-            rd: query.flags.rd,
-            ra: true,
-            ad: true,
-            cd: query.flags.cd,
-            rcode: query.flags.rcode,
+            opcode: Opcode::Query,
+            aa: false,
+            tc: response.tc,
+            rd: response.rd,
+            ra: response.ra,
+            ad: response.ad,
+            cd: response.cd,
+            rcode,
         },
-        additionals: query.additionals,
-        authorities: query.authorities,
-        questions: query.questions,
-        answers: query.answers,
-    })
-    .unwrap();
+        questions: vec![echoed],
+        answers: format_answers(&response.answer.unwrap_or_default()),
+        authorities: format_answers(&response.authority.unwrap_or_default()),
+        additionals: Vec::new(),
+    };
+
+    let encoded = dns
+        .encode()
+        .map_err(|err| ResolveError::InvalidResponse(err.to_string()))?;
 
-    Ok(dns)
+    Ok(encoded.to_vec())
 }
 
-pub async fn resolve(
+/// Resolves the question with the wire format from RFC 8484, relaying the response as it is.
+pub async fn resolve_wire(
     client: &reqwest::Client,
-    name: &str,
-    record_type: &RecordType,
-) -> Result<DnsResponse, Box<dyn Error>> {
-    let config = config::get_config().unwrap();
-    let resolver_ip = config.mode.ip_address();
-
-    let url = format!(
-        "https://{}/dns-query?name={}&type={}&do=1",
-        resolver_ip,
-        urlencoding::encode(name),
-        &record_type.to_string()
-    );
-
-    let res = client
-        .get(&url)
-        .header(reqwest::header::ACCEPT, "application/dns-json")
+    url: &reqwest::Url,
+    protocol: Protocol,
+    question: &wire::Question,
+    dnssec_ok: bool,
+) -> Result<Vec<u8>, ResolveError> {
+    let query = encode_query(question, dnssec_ok);
+
+    let request = match protocol {
+        Protocol::DohGet => client
+            .get(url.clone())
+            .query(&[("dns", BASE64_URL.encode(&query))]),
+        _ => client
+            .post(url.clone())
+            .header(reqwest::header::CONTENT_TYPE, DNS_MESSAGE)
+            .body(query),
+    };
+
+    let res = request
+        .header(reqwest::header::ACCEPT, DNS_MESSAGE)
         .send()
-        .await
-        .expect("error: could not query Cloudflare DOH server");
+        .await?;
 
     let status = res.status();
 
-    if status == reqwest::StatusCode::BAD_REQUEST {
-        panic!("Bad request");
+    if !status.is_success() {
+        return Err(ResolveError::Status(status));
     }
 
-    let dns_response = res.json::<DnsResponse>().await?;
+    let response = res.bytes().await?.to_vec();
+
+    validate_response(&response)?;
+
+    Ok(response)
+}
 
-    Ok(dns_response)
+/// Encodes the query to send upstream for a question from the client.
+///
+/// The ID is always 0, which makes the request cacheable for HTTP caches (RFC 8484 4.1).
+pub fn encode_query(question: &wire::Question, dnssec_ok: bool) -> Vec<u8> {
+    wire::query(
+        question,
+        wire::Edns {
+            payload_size: EDNS_PAYLOAD_SIZE,
+            dnssec_ok,
+            extended_rcode: 0,
+        },
+    )
+}
+
+/// Makes sure a response in wire format is well-formed, before it is cached and relayed.
+pub fn validate_response(response: &[u8]) -> Result<(), ResolveError> {
+    let invalid = |err: WireError| ResolveError::InvalidResponse(err.to_string());
+
+    if !wire::is_response(response).map_err(invalid)? {
+        return Err(ResolveError::InvalidResponse(String::from(
+            "message is not a response",
+        )));
+    }
+
+    wire::records(response).map_err(invalid)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use dns_message_parser::rr::RR;
+
+    use super::{format_answers, DnsAnswer};
+
+    fn answer(r#type: u16, data: &str) -> DnsAnswer {
+        DnsAnswer {
+            domain_name: String::from("example.com."),
+            r#type,
+            ttl: 300,
+            data: String::from(data),
+        }
+    }
+
+    #[test]
+    fn leaves_out_records_it_cant_convert() {
+        let answers = vec![
+            answer(5, "cdn.example.net."),
+            // RRSIG, and a type that isn't assigned
+            answer(
+                46,
+                "A 13 2 300 20300101000000 20200101000000 12345 example.com. c2ln",
+            ),
+            answer(65280, "\\# 4 c0000201"),
+            answer(1, "not an address"),
+            answer(1, "93.184.216.34"),
+        ];
+
+        let records = format_answers(&answers);
+
+        assert!(matches!(records[..], [RR::CNAME(_), RR::A(_)]));
+    }
 }
@@ -0,0 +1,316 @@
+//! Serves DNS over HTTPS (RFC 8484) to clients, such as browsers and phones.
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    io::{self, ErrorKind},
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderValue},
+    server::conn::Http,
+    service::service_fn,
+    Body, Method, Request, Response, StatusCode,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+    time::{self, Instant, Sleep},
+};
+
+use crate::{
+    client::{self, Context, Transport, TCP_IDLE_TIMEOUT},
+    dns::DNS_MESSAGE,
+    tls,
+    wire::{self, Section},
+};
+
+/// The path queries are served on (RFC 8484 4.1).
+const PATH: &str = "/dns-query";
+
+/// The largest DNS message there is, anything bigger can't be a query.
+const MAX_MESSAGE_SIZE: u64 = u16::MAX as u64;
+
+/// The protocols offered to clients with ALPN, HTTP/2 is preferred (RFC 8484 5.2).
+pub const ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+pub async fn serve(listener: TcpListener, acceptor: Arc<tls::Acceptor>, context: Arc<Context>) {
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!("failed to accept doh connection ({})", err);
+
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.get();
+        let context = context.clone();
+
+        tokio::spawn(async move {
+            // Just like for DoT, a client gets as long as an idle connection to finish the handshake
+            let stream = match time::timeout(TCP_IDLE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    debug!("tls handshake with {} failed ({})", src, err);
+
+                    return;
+                }
+                Err(_) => return,
+            };
+
+            let service = service_fn(|request| {
+                let context = context.clone();
+
+                async move { Ok::<_, Infallible>(handle_request(&context, request).await) }
+            });
+
+            let connection = Http::new()
+                .http1_header_read_timeout(TCP_IDLE_TIMEOUT)
+                .serve_connection(IdleTimeout::new(stream), service);
+
+            if let Err(err) = connection.await {
+                debug!("doh connection with {} closed ({})", src, err);
+            }
+        });
+    }
+}
+
+/// A connection that fails once nothing has been read from or written to it for
+/// [`TCP_IDLE_TIMEOUT`], since hyper keeps idle connections open for as long as the client likes.
+struct IdleTimeout<S> {
+    stream: S,
+    timer: Pin<Box<Sleep>>,
+}
+
+impl<S> IdleTimeout<S> {
+    fn new(stream: S) -> IdleTimeout<S> {
+        IdleTimeout {
+            stream,
+            timer: Box::pin(time::sleep(TCP_IDLE_TIMEOUT)),
+        }
+    }
+
+    /// Restarts the timer when the connection made progress, or checks whether it ran out.
+    fn check<T>(
+        &mut self,
+        cx: &mut task::Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        match poll {
+            Poll::Ready(result) => {
+                self.timer.as_mut().reset(Instant::now() + TCP_IDLE_TIMEOUT);
+
+                Poll::Ready(result)
+            }
+            Poll::Pending => match self.timer.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "connection was idle",
+                ))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+
+        self.check(cx, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+
+        self.check(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+async fn handle_request(context: &Arc<Context>, request: Request<Body>) -> Response<Body> {
+    if request.uri().path() != PATH {
+        return status(StatusCode::NOT_FOUND);
+    }
+
+    let query = match *request.method() {
+        Method::GET => {
+            let encoded = request.uri().query().and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|parameter| parameter.strip_prefix("dns="))
+            });
+
+            match encoded.map(|encoded| BASE64_URL.decode(encoded)) {
+                Some(Ok(query)) => query,
+                _ => return status(StatusCode::BAD_REQUEST),
+            }
+        }
+        Method::POST => {
+            let content_type = request.headers().get(header::CONTENT_TYPE);
+
+            if content_type != Some(&HeaderValue::from_static(DNS_MESSAGE)) {
+                return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+
+            let too_large = request
+                .body()
+                .size_hint()
+                .upper()
+                .is_some_and(|size| size > MAX_MESSAGE_SIZE);
+
+            if too_large {
+                return status(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+
+            let body = hyper::body::to_bytes(request.into_body());
+
+            match time::timeout(TCP_IDLE_TIMEOUT, body).await {
+                Ok(Ok(body)) if body.len() as u64 <= MAX_MESSAGE_SIZE => body.to_vec(),
+                Ok(Ok(_)) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+                Ok(Err(_)) => return status(StatusCode::BAD_REQUEST),
+                Err(_) => return status(StatusCode::REQUEST_TIMEOUT),
+            }
+        }
+        _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    let response = match client::handle_packet(context, &query, Transport::Https).await {
+        Some(response) => response,
+        None => return status(StatusCode::BAD_REQUEST),
+    };
+
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, DNS_MESSAGE);
+
+    // The response may be cached by HTTP caches for as long as its records live (RFC 8484 5.1)
+    if let Some(ttl) = min_ttl(&response) {
+        builder = builder.header(header::CACHE_CONTROL, format!("max-age={}", ttl));
+    }
+
+    builder
+        .body(Body::from(response))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// The lowest TTL of the records in the answer and authority sections.
+fn min_ttl(response: &[u8]) -> Option<u32> {
+    wire::records(response)
+        .ok()?
+        .iter()
+        .filter(|record| record.section != Section::Additional)
+        .map(|record| record.ttl)
+        .min()
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+    use hyper::{header, Body, Request, Response, StatusCode};
+    use tokio::time::Duration;
+
+    use super::handle_request;
+    use crate::{
+        dns::DNS_MESSAGE,
+        testing::{context, query},
+        wire,
+    };
+
+    /// Checks that the response is the answer to the query built with [`query`].
+    async fn assert_answered(response: Response<Body>) {
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], DNS_MESSAGE);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        assert_eq!(wire::id(&body), Ok(0x1234));
+        assert_eq!(wire::rcode(&body), Ok(wire::RCODE_NO_ERROR));
+        assert_eq!(wire::question(&body).unwrap().name, "example.com");
+    }
+
+    #[tokio::test]
+    async fn answers_get_requests() {
+        let (context, received) = context(Duration::ZERO).await;
+
+        let uri = format!(
+            "/dns-query?ct&dns={}",
+            BASE64_URL.encode(query("example.com", 1, None))
+        );
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+
+        assert_answered(handle_request(&context, request).await).await;
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_post_requests() {
+        let (context, received) = context(Duration::ZERO).await;
+
+        let request = Request::post("/dns-query")
+            .header(header::CONTENT_TYPE, DNS_MESSAGE)
+            .body(Body::from(query("example.com", 1, None)))
+            .unwrap();
+
+        assert_answered(handle_request(&context, request).await).await;
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_requests() {
+        let (context, received) = context(Duration::ZERO).await;
+
+        let request = Request::post("/dns-query")
+            .header(header::CONTENT_TYPE, "application/dns-json")
+            .body(Body::from(query("example.com", 1, None)))
+            .unwrap();
+
+        let response = handle_request(&context, request).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let request = Request::get("/dns-query?dns=not+base64")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = handle_request(&context, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let request = Request::get("/").body(Body::empty()).unwrap();
+
+        let response = handle_request(&context, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        assert!(received.lock().unwrap().is_empty());
+    }
+}
@@ -24,11 +24,10 @@ pub struct Domain {
 
 impl From<&str> for Domain {
     fn from(value: &str) -> Self {
-        let name = parse(value);
+        // Names are case-insensitive (RFC 4343), so `GoOgle.com` is the same name as `google.com`
+        let name = parse(value).to_ascii_lowercase();
 
-        Domain {
-            name: name.to_string(),
-        }
+        Domain { name }
     }
 }
 
@@ -40,5 +39,6 @@ mod tests {
     fn parses_domain() {
         assert_eq!(Domain::from("signal.org.").name, "signal.org");
         assert_eq!(Domain::from("signal.org").name, "signal.org");
+        assert_eq!(Domain::from("SiGnAl.org.").name, "signal.org");
     }
 }
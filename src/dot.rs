@@ -0,0 +1,505 @@
+//! A client for DNS over TLS (RFC 7858).
+//!
+//! Each upstream keeps a single TLS connection open, which every query is pipelined over. The
+//! responses may arrive in any order, so they are matched to their query by ID.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::TcpStream,
+    sync::{self, oneshot},
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::{
+    dns::{self, ResolveError},
+    plain, wire,
+};
+
+type Stream = TlsStream<TcpStream>;
+
+/// Checks the certificate as usual, and then makes sure its public key is the pinned one.
+#[derive(Debug)]
+struct PinnedVerifier {
+    verifier: Arc<WebPkiServerVerifier>,
+    /// The SHA-256 digest of the SubjectPublicKeyInfo of the certificate
+    pin: [u8; 32],
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let certificate = webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|err| rustls::Error::General(err.to_string()))?;
+
+        let digest = Sha256::digest(certificate.subject_public_key_info());
+
+        if digest.as_slice() != self.pin {
+            return Err(rustls::Error::General(String::from(
+                "certificate doesn't match the pinned public key",
+            )));
+        }
+
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+/// An open connection, shared by every query that is sent while it lasts.
+struct Connection {
+    writer: sync::Mutex<WriteHalf<Stream>>,
+    /// The queries that are waiting for a response, by ID
+    pending: Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>,
+    closed: AtomicBool,
+}
+
+/// Removes a query from the pending ones when it's done, or when it's cancelled (e.g. on timeout).
+struct PendingQuery<'a> {
+    connection: &'a Connection,
+    id: u16,
+}
+
+impl Drop for PendingQuery<'_> {
+    fn drop(&mut self) {
+        self.connection.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Connection {
+    async fn send(&self, mut query: Vec<u8>) -> Result<Vec<u8>, ResolveError> {
+        let (sender, receiver) = oneshot::channel();
+
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+
+            let mut id = rand::random();
+
+            while pending.contains_key(&id) {
+                id = rand::random();
+            }
+
+            pending.insert(id, sender);
+
+            id
+        };
+
+        let _pending = PendingQuery {
+            connection: self,
+            id,
+        };
+
+        wire::set_id(&mut query, id)
+            .map_err(|err| ResolveError::InvalidResponse(err.to_string()))?;
+
+        {
+            let mut writer = self.writer.lock().await;
+
+            let written = async {
+                writer.write_u16(query.len() as u16).await?;
+                writer.write_all(&query).await?;
+                writer.flush().await
+            };
+
+            if let Err(err) = written.await {
+                self.closed.store(true, Ordering::Relaxed);
+
+                return Err(err.into());
+            }
+        }
+
+        // The sender is dropped if the connection closes before the response arrives
+        let response = receiver.await.map_err(|_| {
+            ResolveError::Io(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "connection closed before the response arrived",
+            ))
+        })?;
+
+        if !plain::is_reply(&response, &query) {
+            return Err(ResolveError::InvalidResponse(String::from(
+                "response doesn't match the query",
+            )));
+        }
+
+        dns::validate_response(&response)?;
+
+        Ok(response)
+    }
+
+    /// Reads responses and hands them to the queries waiting for them, until the connection closes.
+    async fn read_responses(&self, mut reader: ReadHalf<Stream>) {
+        let result: io::Result<()> = async {
+            loop {
+                let length = reader.read_u16().await?;
+                let mut response = vec![0; length.into()];
+
+                reader.read_exact(&mut response).await?;
+
+                let sender = match wire::id(&response) {
+                    Ok(id) => self.pending.lock().unwrap().remove(&id),
+                    Err(_) => None,
+                };
+
+                match sender {
+                    Some(sender) => {
+                        let _ = sender.send(response);
+                    }
+                    None => debug!("ignoring a response over tls that doesn't match any query"),
+                }
+            }
+        }
+        .await;
+
+        if let Err(err) = result {
+            debug!("tls connection closed ({})", err);
+        }
+
+        self.closed.store(true, Ordering::Relaxed);
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+/// A DoT server, with the connection to it.
+pub struct TlsUpstream {
+    /// The address to connect to, either `ip:port` or `host:port`
+    address: String,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    connection: sync::Mutex<Option<Arc<Connection>>>,
+}
+
+impl TlsUpstream {
+    pub fn new(
+        address: String,
+        server_name: &str,
+        pin: Option<&str>,
+    ) -> Result<TlsUpstream, Box<dyn Error>> {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+
+        TlsUpstream::with_roots(address, server_name, pin, roots)
+    }
+
+    /// Like [`TlsUpstream::new`], but trusting other root certificates than the usual ones.
+    fn with_roots(
+        address: String,
+        server_name: &str,
+        pin: Option<&str>,
+        roots: RootCertStore,
+    ) -> Result<TlsUpstream, Box<dyn Error>> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let roots = Arc::new(roots);
+
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let config = match pin {
+            Some(pin) => {
+                let pin = BASE64
+                    .decode(pin)
+                    .ok()
+                    .and_then(|pin| <[u8; 32]>::try_from(pin).ok())
+                    .ok_or_else(|| format!("`{}` is not a base64 encoded SHA-256 digest", pin))?;
+
+                let verifier =
+                    WebPkiServerVerifier::builder_with_provider(roots, provider).build()?;
+
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(PinnedVerifier { verifier, pin }))
+                    .with_no_client_auth()
+            }
+            None => builder.with_root_certificates(roots).with_no_client_auth(),
+        };
+
+        Ok(TlsUpstream {
+            address,
+            server_name: ServerName::try_from(server_name.to_owned())?,
+            connector: TlsConnector::from(Arc::new(config)),
+            connection: sync::Mutex::new(None),
+        })
+    }
+
+    /// Returns the open connection, or opens a new one if there isn't one (anymore).
+    async fn connection(&self) -> Result<Arc<Connection>, ResolveError> {
+        let mut connection = self.connection.lock().await;
+
+        if let Some(connection) = connection.as_ref() {
+            if !connection.closed.load(Ordering::Relaxed) {
+                return Ok(connection.clone());
+            }
+        }
+
+        let stream = TcpStream::connect(&self.address).await?;
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+
+        debug!("opened tls connection to {}", self.address);
+
+        let (reader, writer) = tokio::io::split(stream);
+
+        let opened = Arc::new(Connection {
+            writer: sync::Mutex::new(writer),
+            pending: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        });
+
+        let reading = opened.clone();
+
+        tokio::spawn(async move { reading.read_responses(reader).await });
+
+        *connection = Some(opened.clone());
+
+        Ok(opened)
+    }
+
+    pub async fn resolve(
+        &self,
+        question: &wire::Question,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ResolveError> {
+        let query = dns::encode_query(question, dnssec_ok);
+
+        let connection = self.connection().await?;
+
+        match connection.send(query.clone()).await {
+            // The server may have closed the connection while it was idle, which we only find
+            // out about when using it. That deserves one more try with a new connection.
+            Err(ResolveError::Io(err)) if !connection.closed.load(Ordering::Relaxed) => {
+                Err(ResolveError::Io(err))
+            }
+            Err(ResolveError::Io(_)) => self.connection().await?.send(query).await,
+            result => result,
+        }
+    }
+}
+
+impl Display for TlsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, sync::Arc};
+
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use rcgen::CertifiedKey;
+    use rustls::{
+        pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+        ServerConfig,
+    };
+    use sha2::{Digest, Sha256};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+    use super::TlsUpstream;
+    use crate::{
+        testing::{certificate, roots, SERVER_NAME},
+        wire,
+    };
+
+    /// Starts a DoT server with a self-signed certificate, which serves every connection with
+    /// `serve`. Returns its address and certificate.
+    async fn server<F, S>(serve: F) -> (String, CertifiedKey)
+    where
+        F: Fn(TlsStream<TcpStream>) -> S + Send + Sync + 'static,
+        S: Future<Output = ()> + Send,
+    {
+        let certified = certificate();
+        let key =
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![certified.cert.der().clone()], key)
+                .unwrap();
+
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let serve = Arc::new(serve);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                let serve = serve.clone();
+
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        serve(stream).await;
+                    }
+                });
+            }
+        });
+
+        (address, certified)
+    }
+
+    /// Returns an upstream for the server, which trusts its certificate.
+    fn upstream(address: String, certified: &CertifiedKey, pin: Option<&str>) -> TlsUpstream {
+        TlsUpstream::with_roots(address, SERVER_NAME, pin, roots(certified)).unwrap()
+    }
+
+    async fn read_query(stream: &mut TlsStream<TcpStream>) -> Vec<u8> {
+        let length = stream.read_u16().await.unwrap();
+        let mut query = vec![0; length.into()];
+        stream.read_exact(&mut query).await.unwrap();
+
+        query
+    }
+
+    /// Answers a query with an empty response.
+    async fn reply(stream: &mut TlsStream<TcpStream>, query: &[u8]) {
+        let mut response = query.to_vec();
+        response[2] |= 0x80;
+
+        stream.write_u16(response.len() as u16).await.unwrap();
+        stream.write_all(&response).await.unwrap();
+    }
+
+    /// Answers every query on the connection as it arrives.
+    async fn answer(mut stream: TlsStream<TcpStream>) {
+        while let Ok(length) = stream.read_u16().await {
+            let mut query = vec![0; length.into()];
+            stream.read_exact(&mut query).await.unwrap();
+
+            reply(&mut stream, &query).await;
+        }
+    }
+
+    fn question(name: &str) -> wire::Question {
+        wire::Question::new(name, 1).unwrap()
+    }
+
+    #[tokio::test]
+    async fn checks_the_pinned_public_key() {
+        let (address, certified) = server(answer).await;
+
+        // Just like `openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`
+        let pin = BASE64.encode(Sha256::digest(certified.key_pair.public_key_der()));
+        let pinned = upstream(address.clone(), &certified, Some(&pin));
+
+        assert!(pinned
+            .resolve(&question("example.com"), false)
+            .await
+            .is_ok());
+
+        let other = BASE64.encode(Sha256::digest(b"some other key"));
+        let mismatched = upstream(address.clone(), &certified, Some(&other));
+
+        assert!(mismatched
+            .resolve(&question("example.com"), false)
+            .await
+            .is_err());
+
+        // Without a pin, the certificate only has to be trusted
+        let unpinned = upstream(address, &certified, None);
+
+        assert!(unpinned
+            .resolve(&question("example.com"), false)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn matches_responses_out_of_order() {
+        let (address, certified) = server(|mut stream| async move {
+            let first = read_query(&mut stream).await;
+            let second = read_query(&mut stream).await;
+
+            reply(&mut stream, &second).await;
+            reply(&mut stream, &first).await;
+        })
+        .await;
+
+        let upstream = upstream(address, &certified, None);
+
+        let (a, b) = (question("a.example"), question("b.example"));
+        let (a, b) = tokio::join!(upstream.resolve(&a, false), upstream.resolve(&b, false));
+
+        assert_eq!(wire::question(&a.unwrap()).unwrap().name, "a.example");
+        assert_eq!(wire::question(&b.unwrap()).unwrap().name, "b.example");
+    }
+
+    #[tokio::test]
+    async fn fails_queries_when_the_connection_closes() {
+        let (address, certified) = server(|mut stream| async move {
+            read_query(&mut stream).await;
+        })
+        .await;
+
+        let upstream = upstream(address, &certified, None);
+
+        assert!(upstream
+            .resolve(&question("example.com"), false)
+            .await
+            .is_err());
+    }
+}
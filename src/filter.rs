@@ -1,30 +1,16 @@
 use std::{
-    fs::File,
-    io::{BufRead, BufReader},
-    path::PathBuf,
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
 };
 
 use wildmatch::WildMatch;
 
-use crate::domain::Domain;
+use crate::{config, domain::Domain};
 
-pub mod whitelist {
-
-    use crate::config;
-
-    use super::FilterEntry;
-
-    pub fn find(name: &str) -> Option<FilterEntry> {
-        let whitelist_path = config::config_location().join("rules/whitelist.txt");
-        let exists = whitelist_path.try_exists().unwrap();
-
-        if !exists {
-            return None;
-        }
-
-        super::enumerate(&whitelist_path, name)
-    }
-}
+/// The file in the rules directory with the domains that mustn't be blacklisted.
+const WHITELIST_FILE: &str = "whitelist.txt";
 
 pub struct FilterEntry {
     pub file: String,
@@ -41,127 +27,311 @@ impl FilterEntry {
     }
 }
 
-pub mod blacklist {
-    use std::{
-        fs::{self},
-        path::PathBuf,
-    };
-
-    use crate::config;
-
-    use super::FilterEntry;
-
-    pub fn find(name: &str) -> Option<FilterEntry> {
-        if super::whitelist::find(name).is_some() {
-            return None;
-        }
+/// A pattern, along with where it was read from.
+struct Rule {
+    pattern: String,
+    /// The index of the file in [`Rules::files`]
+    file: usize,
+    line: usize,
+}
 
-        let directory_path = config::config_location().join("rules");
-        let directory_read = fs::read_dir(&directory_path);
+/// A level of the trie, with the labels below it (e.g. `example` below `com`).
+#[derive(Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    /// The rule matching the name that ends at this node
+    exact: Option<usize>,
+    /// The rule matching every subdomain of the name that ends at this node
+    subdomains: Option<usize>,
+}
 
-        if directory_read.is_err() {
-            return None;
-        }
+/// A set of patterns, read once and indexed so that a name can be matched against all of them at
+/// once. When several patterns match, the one that was added first wins.
+///
+/// Most patterns are a domain (`example.com`), its subdomains (`*.example.com`) or both
+/// (`**.example.com`), which are kept in a trie of labels from right to left. Only the few with
+/// wildcards anywhere else have to be matched one by one.
+#[derive(Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    files: Vec<String>,
+    root: Node,
+    /// The patterns that don't fit in the trie, in the order they were added
+    wildcards: Vec<(WildMatch, usize)>,
+}
 
-        let directory = directory_read.unwrap();
+fn is_literal(pattern: &str) -> bool {
+    !pattern.is_empty() && !pattern.contains(['*', '?'])
+}
 
-        let files: Vec<PathBuf> = directory
-            .filter(|object| {
-                let file = object.as_ref().expect("Should always be Ok");
-                let path = file.path();
-                let path_name = path.to_string_lossy().to_string();
+/// Whichever of the two rules was added first.
+fn earliest(rule: Option<usize>, other: Option<usize>) -> Option<usize> {
+    rule.into_iter().chain(other).min()
+}
 
-                if !path.is_file() {
-                    return false;
-                }
+impl Rules {
+    /// Reads the patterns in a file, one on each line. Empty lines and comments (starting with
+    /// `#`) are skipped.
+    pub fn read(&mut self, path: &Path) -> io::Result<()> {
+        let reader = BufReader::new(File::open(path)?);
 
-                if path_name == "whitelist.txt" || !path_name.ends_with(".txt") {
-                    return false;
-                }
+        self.files.push(path.to_string_lossy().to_string());
 
-                true
-            })
-            .map(|object| object.unwrap().path())
-            .collect();
+        let file = self.files.len() - 1;
 
-        for path in files {
-            let result = super::enumerate(&path, name);
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let pattern = line.trim();
 
-            if result.is_some() {
-                return result;
+            if pattern.starts_with('#') || pattern.is_empty() {
+                continue;
             }
+
+            self.add(pattern, file, index + 1);
         }
 
-        None
+        Ok(())
     }
-}
 
-/// Enumerates the file and matches patterns against the domain name
-fn enumerate(path: &PathBuf, name: &str) -> Option<FilterEntry> {
-    let file = File::open(path).unwrap();
-    let reader = BufReader::new(file);
+    /// Builds rules from a list of patterns, e.g. from the configuration. `source` takes the place
+    /// of the file they were read from, and their position in the list that of the line.
+    pub fn from_patterns(source: &str, patterns: &[impl AsRef<str>]) -> Rules {
+        let mut rules = Rules::default();
 
-    for (index, entry) in reader.lines().enumerate() {
-        let line = entry.unwrap();
-        let pattern = line.trim();
+        rules.files.push(source.to_owned());
 
-        if pattern.starts_with('#') || pattern.is_empty() {
-            continue;
+        for (index, pattern) in patterns.iter().enumerate() {
+            rules.add(pattern.as_ref().trim(), 0, index + 1);
         }
 
-        let filename = path.to_string_lossy().to_string();
+        rules
+    }
 
-        let line_number = index + 1;
+    fn add(&mut self, pattern: &str, file: usize, line: usize) {
+        // Names are compared in lowercase, since they're case-insensitive (RFC 4343)
+        let pattern = pattern.to_ascii_lowercase();
+        let index = self.rules.len();
 
         // This is a globstar pattern, a shorthand for blacklisting a domain and all it's subdomains.
         //
         // The pattern `**.example.com` will be "unwrapped" to two distinct patterns:
         // `example.com` and `*.example.com`
-        if pattern.starts_with("**.") {
-            let domain_pattern = pattern.strip_prefix("**.").unwrap();
-            let subdomain_pattern = format!("*.{}", domain_pattern);
-
-            if WildMatch::new(domain_pattern).matches(name)
-                || WildMatch::new(&subdomain_pattern).matches(name)
-            {
-                return Some(FilterEntry {
-                    file: filename,
-                    pattern: pattern.to_string(),
-                    line: line_number,
-                });
+        if let Some(domain) = pattern
+            .strip_prefix("**.")
+            .filter(|domain| is_literal(domain))
+        {
+            let node = self.node(domain);
+
+            node.exact = earliest(node.exact, Some(index));
+            node.subdomains = earliest(node.subdomains, Some(index));
+        } else if let Some(domain) = pattern
+            .strip_prefix("*.")
+            .filter(|domain| is_literal(domain))
+        {
+            let node = self.node(domain);
+
+            node.subdomains = earliest(node.subdomains, Some(index));
+        } else if is_literal(&pattern) {
+            let node = self.node(&pattern);
+
+            node.exact = earliest(node.exact, Some(index));
+        } else {
+            if let Some(domain) = pattern.strip_prefix("**.") {
+                self.wildcards.push((WildMatch::new(domain), index));
+                self.wildcards
+                    .push((WildMatch::new(&format!("*.{}", domain)), index));
             }
+
+            self.wildcards.push((WildMatch::new(&pattern), index));
         }
 
-        if WildMatch::new(pattern).matches(name) {
-            return Some(FilterEntry {
-                file: filename,
-                pattern: pattern.to_string(),
-                line: line_number,
-            });
+        self.rules.push(Rule {
+            pattern,
+            file,
+            line,
+        });
+    }
+
+    /// Returns the node for a domain, adding it (and the ones above it) if it isn't there yet.
+    fn node(&mut self, domain: &str) -> &mut Node {
+        domain.rsplit('.').fold(&mut self.root, |node, label| {
+            node.children.entry(label.to_owned()).or_default()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Returns the first rule that matches the name, which has to be in lowercase.
+    pub fn find(&self, name: &str) -> Option<FilterEntry> {
+        let mut found = None;
+        let mut node = &self.root;
+        let mut labels = name.rsplit('.');
+
+        loop {
+            let Some(label) = labels.next() else {
+                found = earliest(found, node.exact);
+                break;
+            };
+
+            // There's at least one more label, so the name is a subdomain of this node
+            found = earliest(found, node.subdomains);
+
+            match node.children.get(label) {
+                Some(child) => node = child,
+                None => break,
+            }
         }
+
+        // Only the wildcards that were added before the rule found so far can take precedence
+        let wildcard = self
+            .wildcards
+            .iter()
+            .take_while(|(_, index)| found.is_none_or(|found| *index < found))
+            .find(|(pattern, _)| pattern.matches(name))
+            .map(|(_, index)| *index);
+
+        let rule = &self.rules[wildcard.or(found)?];
+
+        Some(FilterEntry {
+            file: self.files[rule.file].clone(),
+            pattern: rule.pattern.clone(),
+            line: rule.line,
+        })
     }
+}
+
+/// The blacklist and whitelist, from the `.txt` files in the rules directory. They are read once,
+/// when we start.
+#[derive(Default)]
+pub struct Filter {
+    blacklist: Rules,
+    whitelist: Rules,
+}
+
+impl Filter {
+    /// Loads the rules from `rules/` in the configuration directory. Files are read in order of
+    /// their names, which decides which rule is reported when several of them match.
+    pub fn load() -> Filter {
+        let directory = config::config_location().join("rules");
+        let mut filter = Filter::default();
+
+        let mut files: Vec<PathBuf> = match fs::read_dir(&directory) {
+            Ok(entries) => entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "txt"))
+                .collect(),
+            Err(_) => return filter,
+        };
+
+        files.sort();
+
+        for path in files {
+            let rules = if path.file_name().is_some_and(|name| name == WHITELIST_FILE) {
+                &mut filter.whitelist
+            } else {
+                &mut filter.blacklist
+            };
+
+            if let Err(err) = rules.read(&path) {
+                warn!("failed to read rules from `{}` ({})", path.display(), err);
+            }
+        }
+
+        debug!(
+            "loaded {} blacklist and {} whitelist rules",
+            filter.blacklist.len(),
+            filter.whitelist.len()
+        );
+
+        filter
+    }
+
+    /// Returns the rule that blacklists the name, unless it's whitelisted.
+    pub fn find(&self, name: &str) -> Option<FilterEntry> {
+        if self.whitelist.find(name).is_some() {
+            return None;
+        }
 
-    None
+        self.blacklist.find(name)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::blacklist;
+    use crate::domain::Domain;
+
+    use super::{Filter, Rules};
+
+    fn compile(patterns: &[&str]) -> Rules {
+        Rules::from_patterns("test.txt", patterns)
+    }
 
     #[test]
     fn filters_bad_domains() {
-        assert!(blacklist::find("google.com").is_some());
-        assert!(blacklist::find("maps.google.com").is_some());
-        assert!(blacklist::find("google-analytics.com").is_some());
-        assert!(blacklist::find("tiktokv.com").is_some());
-        assert!(blacklist::find("facebook.com").is_some());
-        assert!(blacklist::find("doubleclick.net").is_some());
+        let filter = Filter::load();
+
+        assert!(filter.find("google.com").is_some());
+        assert!(filter.find("maps.google.com").is_some());
+        assert!(filter.find("google-analytics.com").is_some());
+        assert!(filter.find("tiktokv.com").is_some());
+        assert!(filter.find("facebook.com").is_some());
+        assert!(filter.find("doubleclick.net").is_some());
+
+        // Names are case-insensitive
+        assert!(filter.find(&Domain::from("GoOgle.com.").name).is_some());
     }
 
     #[test]
     fn allows_good_domains() {
-        assert!(blacklist::find("duckduckgo.com").is_none());
-        assert!(blacklist::find("signal.org").is_none());
-        assert!(blacklist::find("tutanota.com").is_none());
+        let filter = Filter::load();
+
+        assert!(filter.find("duckduckgo.com").is_none());
+        assert!(filter.find("signal.org").is_none());
+        assert!(filter.find("tutanota.com").is_none());
+    }
+
+    #[test]
+    fn matches_patterns() {
+        let rules = compile(&[
+            "ads.invasive.web",
+            "*.example.com",
+            "**.Tracker.net",
+            "ads.*.example.org",
+            "**.cdn*.com",
+        ]);
+
+        let line = |name: &str| rules.find(name).map(|entry| entry.line);
+
+        assert_eq!(line("ads.invasive.web"), Some(1));
+        assert_eq!(line("invasive.web"), None);
+        assert_eq!(line("more.ads.invasive.web"), None);
+
+        assert_eq!(line("mail.example.com"), Some(2));
+        assert_eq!(line("james.blog.example.com"), Some(2));
+        assert_eq!(line("example.com"), None);
+
+        assert_eq!(line("tracker.net"), Some(3));
+        assert_eq!(line("pixel.tracker.net"), Some(3));
+        assert_eq!(line("nottracker.net"), None);
+
+        assert_eq!(line("ads.shop.example.org"), Some(4));
+        assert_eq!(line("shop.example.org"), None);
+
+        assert_eq!(line("cdn1.com"), Some(5));
+        assert_eq!(line("img.cdn1.com"), Some(5));
+    }
+
+    #[test]
+    fn reports_the_first_matching_rule() {
+        let rules = compile(&["*.example.com", "**.example.com", "ads.example.com"]);
+
+        assert_eq!(rules.find("ads.example.com").unwrap().line, 1);
+        assert_eq!(rules.find("example.com").unwrap().line, 2);
+
+        let rules = compile(&["ads.*", "ads.example.com"]);
+
+        assert_eq!(rules.find("ads.example.com").unwrap().line, 1);
     }
 }
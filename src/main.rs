@@ -3,20 +3,37 @@ extern crate log;
 
 use std::{error::Error, net::SocketAddr};
 
+use cache::Cache;
 use dns::RecordType;
 use domain::Domain;
 use env_logger::Builder;
+use filter::Filter;
 use log::LevelFilter;
+use snapshot::Snapshot;
+use upstream::Forwarder;
 
-use clap::{crate_description, crate_version, Arg, Command};
+use clap::{crate_description, crate_version, Arg, ArgAction, Command};
+use config::{ListenProtocol, ListenerConfig};
 use serde::Deserialize;
 
 mod cache;
 mod client;
 mod config;
 mod dns;
+mod doh;
 mod domain;
+mod dot;
 mod filter;
+mod plain;
+mod record;
+mod shutdown;
+mod snapshot;
+mod systemd;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod upstream;
+mod wire;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -30,12 +47,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let conf = config::get_config().expect("Config should be valid");
 
-    let reqw_client = if conf.tor {
-        let proxy = reqwest::Proxy::all("socks5h://127.0.0.1:9050")
-            .expect("Could not find tor proxy at 127.0.0.1:9050");
-
-        let client = reqwest::Client::builder()
-            .proxy(proxy)
+    if conf.tor {
+        let client = upstream::client_builder(true)
             .build()
             .expect("Should be able to build client");
 
@@ -45,11 +58,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
         let is_tor = text.contains("Congratulations. This browser is configured to use Tor.");
 
         assert!(is_tor, "did not successfully connect to tor");
+    }
 
-        client
-    } else {
-        reqwest::Client::new()
-    };
+    let forwarder = Forwarder::from_config(&conf)?;
 
     let matches = Command::new("swiftdns")
         .version(crate_version!())
@@ -61,8 +72,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     .short('a')
                     .long("address")
                     .required(false)
+                    .action(ArgAction::Append)
                     .value_parser(clap::value_parser!(SocketAddr))
-                    .help("Specify the address for the DNS client to listen on"),
+                    .help("Specify an address to listen on over UDP and TCP, instead of the configured ones (can be given more than once)"),
             ),
         )
         .subcommand(
@@ -77,7 +89,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .arg(
                     Arg::new("type")
                         .short('t')
-                        .help("The type of record to resolve (A, AAAA)")
+                        .help("The type of record to resolve (e.g. A, AAAA, MX, TXT)")
                         .default_value("A")
                         .value_parser(clap::value_parser!(RecordType)),
                 ),
@@ -86,48 +98,65 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     match matches.subcommand() {
         Some(("start", start_match)) => {
-            let debug_addr = "127.0.0.1:5053".parse::<SocketAddr>().unwrap();
-            let release_addr = conf.address;
-
-            let addr = {
-                if let Some(specified_addr) = start_match.get_one::<SocketAddr>("address") {
-                    specified_addr
-                } else if cfg!(debug_assertions) {
-                    &debug_addr
-                } else {
-                    &release_addr
+            let mut listeners = conf.listeners()?;
+
+            let addresses: Vec<SocketAddr> = match start_match.get_many::<SocketAddr>("address") {
+                Some(addresses) => addresses.copied().collect(),
+                None if cfg!(debug_assertions) => vec!["127.0.0.1:5053".parse().unwrap()],
+                None => Vec::new(),
+            };
+
+            // Addresses given on the command line replace the plain DNS listeners, those over TLS are kept
+            if !addresses.is_empty() {
+                for listener in &mut listeners {
+                    listener
+                        .protocols
+                        .retain(|protocol| !matches!(protocol, ListenProtocol::Udp | ListenProtocol::Tcp));
                 }
+
+                listeners.retain(|listener| !listener.protocols.is_empty());
+                listeners.extend(addresses.into_iter().map(ListenerConfig::plain));
+            }
+
+            // Sockets passed by systemd take the place of the configured listeners on the same address
+            let sockets = match systemd::sockets()? {
+                Some(sockets) => client::Source::with_inherited(sockets, &listeners),
+                None => client::Source::from_config(&listeners),
             };
 
-            client::start(addr, reqw_client).await;
+            let listeners = client::listeners(sockets, &conf)?;
+
+            let mut cache = Cache::new(&conf);
+            let snapshot = Snapshot::from_config(&conf);
+
+            if let Some(snapshot) = &snapshot {
+                snapshot.load(&mut cache);
+            }
+
+            client::start(listeners, forwarder, Filter::load(), cache, snapshot).await;
         },
         Some(("resolve", resolve_match)) => {
             let domain = resolve_match.get_one::<Domain>("name").unwrap();
             let record_type = resolve_match.get_one::<RecordType>("type").unwrap();
 
-            if let Some(entry) = filter::blacklist::find(&domain.name) {
+            if let Some(entry) = Filter::load().find(&domain.name) {
                 info!("{}", entry.format_message(domain));
 
                 return Ok(());
             }
 
-            let response = dns::resolve(&reqw_client, &domain.name, record_type).await.unwrap();
+            let question = wire::Question::new(&domain.name, record_type.value())?;
 
-            if let Some(answer) = response.answer {
-                let record = answer.first().expect("Answer should have at least 1 entry");
+            let response = forwarder.resolve(&question, false).await?;
+            let response = dns::decode(&response)?;
 
+            if let Some(record) = response.answers.first() {
                 info!(
                     "the `{}` record for `{}` was resolved to {}",
-                    record_type.to_string(),
-                    domain.name,
-                    record.data
+                    record_type, domain.name, record
                 );
             } else {
-                info!(
-                    "no `{}` record exists for {}",
-                    record_type.to_string(),
-                    domain.name
-                );
+                info!("no `{}` record exists for {}", record_type, domain.name);
             }
         }
         _ => panic!("Something went wrong. A subcommand was provided and accepted by clap but not caught by match"),
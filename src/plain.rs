@@ -0,0 +1,250 @@
+//! A client for classic DNS over UDP and TCP (RFC 1035 4.2), for upstreams that don't speak
+//! DNS over HTTPS, such as routers and internal servers.
+//!
+//! Every query gets a random ID and is sent from a random port, and replies are only accepted if
+//! they match the query, to make it harder to spoof responses.
+
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use rand::Rng;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::{
+    dns::{self, ResolveError},
+    wire,
+};
+
+/// How many random ports to try before letting the system pick one.
+const BIND_ATTEMPTS: usize = 10;
+
+/// Resolves the question over UDP, retrying over TCP if the response is truncated.
+pub async fn resolve_udp(
+    address: SocketAddr,
+    question: &wire::Question,
+    dnssec_ok: bool,
+) -> Result<Vec<u8>, ResolveError> {
+    let query = query(question, dnssec_ok)?;
+    let socket = bind(address).await?;
+
+    socket.connect(address).await?;
+    socket.send(&query).await?;
+
+    let mut buf = vec![0; dns::EDNS_PAYLOAD_SIZE.into()];
+
+    // Anything that doesn't answer our query is ignored, until the real response arrives (or
+    // the upstream times out).
+    loop {
+        let length = socket.recv(&mut buf).await?;
+        let response = &buf[..length];
+
+        if !is_reply(response, &query) {
+            debug!(
+                "ignoring a udp message from {} that doesn't match the query",
+                address
+            );
+
+            continue;
+        }
+
+        dns::validate_response(response)?;
+
+        if wire::is_truncated(response).unwrap_or(false) {
+            debug!("response from {} is truncated, retrying over tcp", address);
+
+            return resolve_tcp(address, question, dnssec_ok).await;
+        }
+
+        return Ok(response.to_vec());
+    }
+}
+
+/// Resolves the question over TCP.
+pub async fn resolve_tcp(
+    address: SocketAddr,
+    question: &wire::Question,
+    dnssec_ok: bool,
+) -> Result<Vec<u8>, ResolveError> {
+    let query = query(question, dnssec_ok)?;
+
+    let mut stream = TcpStream::connect(address).await?;
+
+    stream.write_u16(query.len() as u16).await?;
+    stream.write_all(&query).await?;
+
+    let length = stream.read_u16().await?;
+    let mut response = vec![0; length.into()];
+
+    stream.read_exact(&mut response).await?;
+
+    if !is_reply(&response, &query) {
+        return Err(ResolveError::InvalidResponse(String::from(
+            "response doesn't match the query",
+        )));
+    }
+
+    dns::validate_response(&response)?;
+
+    Ok(response)
+}
+
+/// Encodes the query with a random ID.
+fn query(question: &wire::Question, dnssec_ok: bool) -> Result<Vec<u8>, ResolveError> {
+    let mut query = dns::encode_query(question, dnssec_ok);
+
+    wire::set_id(&mut query, rand::random())
+        .map_err(|err| ResolveError::InvalidResponse(err.to_string()))?;
+
+    Ok(query)
+}
+
+/// Checks whether the message is a response with the same ID and question as the query.
+pub fn is_reply(message: &[u8], query: &[u8]) -> bool {
+    wire::is_response(message).unwrap_or(false)
+        && wire::id(message) == wire::id(query)
+        && wire::same_question(message, query).unwrap_or(false)
+}
+
+/// Binds a UDP socket to a random port, in the address family of the upstream.
+async fn bind(address: SocketAddr) -> std::io::Result<UdpSocket> {
+    let ip: IpAddr = match address {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+
+    for _ in 0..BIND_ATTEMPTS {
+        let port = rand::thread_rng().gen_range(1024..=u16::MAX);
+
+        match UdpSocket::bind((ip, port)).await {
+            Ok(socket) => return Ok(socket),
+            Err(err) if err.kind() == ErrorKind::AddrInUse => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    UdpSocket::bind((ip, 0)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use dns_message_parser::RCode;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UdpSocket},
+    };
+
+    use super::{resolve_tcp, resolve_udp};
+    use crate::{
+        testing::{a, message},
+        wire,
+    };
+
+    /// An answer to a question for `name`, with the ID of the query.
+    fn reply(query: &[u8], name: &str, rcode: RCode) -> Vec<u8> {
+        let mut response = message(name, rcode, vec![a(name, 300)], Vec::new());
+        wire::set_id(&mut response, wire::id(query).unwrap()).unwrap();
+
+        response
+    }
+
+    #[tokio::test]
+    async fn ignores_replies_that_dont_match_the_query() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = upstream.local_addr().unwrap();
+        let question = wire::Question::new("example.com", 1).unwrap();
+
+        let resolving = tokio::spawn(async move { resolve_udp(address, &question, false).await });
+
+        let mut buf = [0; 4096];
+        let (length, client) = upstream.recv_from(&mut buf).await.unwrap();
+        let query = buf[..length].to_vec();
+
+        // From somewhere else than the upstream
+        let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let spoofed = reply(&query, "example.com", RCode::NXDomain);
+
+        spoofer.send_to(&spoofed, client).await.unwrap();
+
+        // With another ID, and for another question
+        let mut other_id = spoofed.clone();
+        wire::set_id(&mut other_id, wire::id(&query).unwrap().wrapping_add(1)).unwrap();
+
+        upstream.send_to(&other_id, client).await.unwrap();
+        upstream
+            .send_to(&reply(&query, "example.org", RCode::NXDomain), client)
+            .await
+            .unwrap();
+
+        upstream
+            .send_to(&reply(&query, "example.com", RCode::NoError), client)
+            .await
+            .unwrap();
+
+        let response = resolving.await.unwrap().unwrap();
+
+        assert_eq!(wire::rcode(&response), Ok(wire::RCODE_NO_ERROR));
+        assert_eq!(wire::id(&response), wire::id(&query));
+    }
+
+    #[tokio::test]
+    async fn retries_truncated_responses_over_tcp() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = upstream.local_addr().unwrap();
+        let listener = TcpListener::bind(address).await.unwrap();
+        let question = wire::Question::new("example.com", 1).unwrap();
+
+        let resolving = tokio::spawn(async move { resolve_udp(address, &question, false).await });
+
+        let mut buf = [0; 4096];
+        let (length, client) = upstream.recv_from(&mut buf).await.unwrap();
+        let query = &buf[..length];
+
+        // Just the header and question, with TC set
+        let mut truncated = message("example.com", RCode::NoError, Vec::new(), Vec::new());
+        wire::set_id(&mut truncated, wire::id(query).unwrap()).unwrap();
+        truncated[2] |= 0x02;
+
+        upstream.send_to(&truncated, client).await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let length = stream.read_u16().await.unwrap();
+        let mut query = vec![0; length.into()];
+        stream.read_exact(&mut query).await.unwrap();
+
+        let response = reply(&query, "example.com", RCode::NoError);
+
+        stream.write_u16(response.len() as u16).await.unwrap();
+        stream.write_all(&response).await.unwrap();
+
+        let response = resolving.await.unwrap().unwrap();
+
+        assert_eq!(wire::is_truncated(&response), Ok(false));
+        assert_eq!(wire::answer_count(&response), Ok(1));
+    }
+
+    #[tokio::test]
+    async fn rejects_tcp_responses_to_other_queries() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let question = wire::Question::new("example.com", 1).unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let length = stream.read_u16().await.unwrap();
+            let mut query = vec![0; length.into()];
+            stream.read_exact(&mut query).await.unwrap();
+
+            let response = reply(&query, "example.org", RCode::NoError);
+
+            stream.write_u16(response.len() as u16).await.unwrap();
+            stream.write_all(&response).await.unwrap();
+        });
+
+        assert!(resolve_tcp(address, &question, false).await.is_err());
+    }
+}
@@ -0,0 +1,448 @@
+//! Conversion of the `data` field in the DoH JSON API into resource records.
+//!
+//! The JSON API returns record data in the presentation format of the zone file (RFC 1035 5.1),
+//! e.g. `10 mail.example.com.` for an MX record. Records that the resolver doesn't know how to
+//! present are returned in the generic format from RFC 3597 instead (`\# <length> <hex>`).
+
+use std::{collections::BTreeSet, convert::TryFrom};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::BufMut;
+use dns_message_parser::{
+    rr::{
+        AlgorithmType, Class, DigestType, ServiceBinding, ServiceParameter, Tag, A, AAAA, CAA,
+        CNAME, DNSKEY, DS, MX, NS, PTR, RR, SOA, SRV, TXT,
+    },
+    DomainName,
+};
+
+use crate::dns::{DnsAnswer, RecordType};
+
+/// Parses a record from the JSON API, returning `None` if the data isn't valid for its type.
+pub fn parse(record_type: &RecordType, answer: &DnsAnswer) -> Option<RR> {
+    let data = answer.data.trim();
+
+    if let Some(rdata) = data.strip_prefix("\\#") {
+        return parse_generic(answer, rdata);
+    }
+
+    let domain_name = parse_name(&answer.domain_name)?;
+    let ttl = answer.ttl;
+    let class = Class::IN;
+
+    let tokens = tokenize(data)?;
+    let mut fields = tokens.iter().map(|token| token.as_str());
+
+    let record = match record_type {
+        RecordType::A => RR::A(A {
+            domain_name,
+            ttl,
+            ipv4_addr: data.parse().ok()?,
+        }),
+        RecordType::AAAA => RR::AAAA(AAAA {
+            domain_name,
+            ttl,
+            ipv6_addr: data.parse().ok()?,
+        }),
+        RecordType::CNAME => RR::CNAME(CNAME {
+            domain_name,
+            ttl,
+            class,
+            c_name: parse_name(fields.next()?)?,
+        }),
+        RecordType::NS => RR::NS(NS {
+            domain_name,
+            ttl,
+            class,
+            ns_d_name: parse_name(fields.next()?)?,
+        }),
+        RecordType::PTR => RR::PTR(PTR {
+            domain_name,
+            ttl,
+            class,
+            ptr_d_name: parse_name(fields.next()?)?,
+        }),
+        RecordType::MX => RR::MX(MX {
+            domain_name,
+            ttl,
+            class,
+            preference: fields.next()?.parse().ok()?,
+            exchange: parse_name(fields.next()?)?,
+        }),
+        RecordType::TXT => RR::TXT(TXT {
+            domain_name,
+            ttl,
+            class,
+            strings: tokens.clone().try_into().ok()?,
+        }),
+        RecordType::SOA => RR::SOA(SOA {
+            domain_name,
+            ttl,
+            class,
+            m_name: parse_name(fields.next()?)?,
+            r_name: parse_name(fields.next()?)?,
+            serial: fields.next()?.parse().ok()?,
+            refresh: fields.next()?.parse().ok()?,
+            retry: fields.next()?.parse().ok()?,
+            expire: fields.next()?.parse().ok()?,
+            min_ttl: fields.next()?.parse().ok()?,
+        }),
+        RecordType::SRV => RR::SRV(SRV {
+            domain_name,
+            ttl,
+            class,
+            priority: fields.next()?.parse().ok()?,
+            weight: fields.next()?.parse().ok()?,
+            port: fields.next()?.parse().ok()?,
+            target: parse_name(fields.next()?)?,
+        }),
+        RecordType::CAA => RR::CAA(CAA {
+            domain_name,
+            ttl,
+            class,
+            flags: fields.next()?.parse().ok()?,
+            tag: Tag::try_from(fields.next()?.to_string()).ok()?,
+            value: fields.next()?.as_bytes().to_vec(),
+        }),
+        RecordType::DS => RR::DS(DS {
+            domain_name,
+            ttl,
+            class,
+            key_tag: fields.next()?.parse().ok()?,
+            algorithm_type: AlgorithmType::try_from(fields.next()?.parse::<u8>().ok()?).ok()?,
+            digest_type: DigestType::try_from(fields.next()?.parse::<u8>().ok()?).ok()?,
+            // The digest is allowed to be split up by whitespace
+            digest: hex::decode(fields.collect::<String>()).ok()?,
+        }),
+        RecordType::DNSKEY => {
+            let flags: u16 = fields.next()?.parse().ok()?;
+            let protocol: u8 = fields.next()?.parse().ok()?;
+
+            // The protocol field is fixed (RFC 4034 2.1.2)
+            if protocol != 3 {
+                return None;
+            }
+
+            RR::DNSKEY(DNSKEY {
+                domain_name,
+                ttl,
+                class,
+                zone_key_flag: flags & 0x0100 != 0,
+                secure_entry_point_flag: flags & 0x0001 != 0,
+                algorithm_type: AlgorithmType::try_from(fields.next()?.parse::<u8>().ok()?).ok()?,
+                public_key: BASE64.decode(fields.collect::<String>()).ok()?,
+            })
+        }
+        RecordType::HTTPS | RecordType::SVCB => {
+            let priority: u16 = fields.next()?.parse().ok()?;
+            let target_name = parse_name(fields.next()?)?;
+
+            let mut parameters = BTreeSet::new();
+
+            for field in fields {
+                parameters.insert(parse_service_parameter(field)?);
+            }
+
+            let service_binding = ServiceBinding {
+                name: domain_name,
+                ttl,
+                priority,
+                target_name,
+                parameters,
+                https: *record_type == RecordType::HTTPS,
+            };
+
+            if service_binding.https {
+                RR::HTTPS(service_binding)
+            } else {
+                RR::SVCB(service_binding)
+            }
+        }
+    };
+
+    Some(record)
+}
+
+/// Parses a domain name, which may be fully qualified, or the root (`.`).
+fn parse_name(name: &str) -> Option<DomainName> {
+    if name == "." {
+        return Some(DomainName::default());
+    }
+
+    name.parse().ok()
+}
+
+/// Parses a record in the generic format (RFC 3597 5), by building the wire format of the record
+/// and letting the parser decode it like any other record.
+fn parse_generic(answer: &DnsAnswer, rdata: &str) -> Option<RR> {
+    let mut fields = rdata.split_whitespace();
+
+    let length: usize = fields.next()?.parse().ok()?;
+    let rdata = hex::decode(fields.collect::<String>()).ok()?;
+
+    if rdata.len() != length {
+        return None;
+    }
+
+    let domain_name = parse_name(&answer.domain_name)?;
+
+    let mut wire = domain_name.encode().ok()?;
+    wire.put_u16(answer.r#type);
+    wire.put_u16(Class::IN as u16);
+    wire.put_u32(answer.ttl);
+    wire.put_u16(length.try_into().ok()?);
+    wire.put_slice(&rdata);
+
+    RR::decode(wire.freeze()).ok()
+}
+
+/// Parses a single `key=value` parameter of an SVCB or HTTPS record (RFC 9460 2.1).
+fn parse_service_parameter(field: &str) -> Option<ServiceParameter> {
+    let (key, value) = field.split_once('=').unwrap_or((field, ""));
+
+    let parameter = match key {
+        "mandatory" => ServiceParameter::MANDATORY {
+            key_ids: value
+                .split(',')
+                .map(service_parameter_key)
+                .collect::<Option<Vec<u16>>>()?,
+        },
+        "alpn" => ServiceParameter::ALPN {
+            alpn_ids: value.split(',').map(String::from).collect(),
+        },
+        "no-default-alpn" => ServiceParameter::NO_DEFAULT_ALPN,
+        "port" => ServiceParameter::PORT {
+            port: value.parse().ok()?,
+        },
+        "ipv4hint" => ServiceParameter::IPV4_HINT {
+            hints: value
+                .split(',')
+                .map(|hint| hint.parse().ok())
+                .collect::<Option<Vec<_>>>()?,
+        },
+        "ipv6hint" => ServiceParameter::IPV6_HINT {
+            hints: value
+                .split(',')
+                .map(|hint| hint.parse().ok())
+                .collect::<Option<Vec<_>>>()?,
+        },
+        "ech" => ServiceParameter::ECH {
+            config_list: BASE64.decode(value).ok()?,
+        },
+        key => {
+            let number = service_parameter_key(key)?;
+
+            // Only the keys reserved for private use can be represented by the parser
+            if !(65280..=65534).contains(&number) {
+                return None;
+            }
+
+            ServiceParameter::PRIVATE {
+                number,
+                wire_data: value.as_bytes().to_vec(),
+            }
+        }
+    };
+
+    Some(parameter)
+}
+
+/// Maps the name of a service parameter key to its registered number.
+fn service_parameter_key(key: &str) -> Option<u16> {
+    let number = match key {
+        "mandatory" => 0,
+        "alpn" => 1,
+        "no-default-alpn" => 2,
+        "port" => 3,
+        "ipv4hint" => 4,
+        "ech" => 5,
+        "ipv6hint" => 6,
+        key => key.strip_prefix("key")?.parse().ok()?,
+    };
+
+    Some(number)
+}
+
+/// Splits record data into whitespace-separated fields.
+///
+/// Fields may be quoted to include whitespace, and both `\X` and `\DDD` escapes are resolved,
+/// so `"v=spf1 -all" "second"` yields the two strings `v=spf1 -all` and `second`.
+fn tokenize(data: &str) -> Option<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut bytes = data.bytes().peekable();
+
+    loop {
+        while bytes.next_if(u8::is_ascii_whitespace).is_some() {}
+
+        let quoted = match bytes.peek() {
+            None => break,
+            Some(b'"') => {
+                bytes.next();
+                true
+            }
+            Some(_) => false,
+        };
+
+        let mut token = Vec::new();
+
+        loop {
+            match bytes.next() {
+                None if quoted => return None,
+                None => break,
+                Some(b'"') if quoted => break,
+                Some(byte) if !quoted && byte.is_ascii_whitespace() => break,
+                Some(b'\\') => {
+                    let escaped = bytes.next()?;
+
+                    if escaped.is_ascii_digit() {
+                        let digits = [escaped, bytes.next()?, bytes.next()?];
+                        let value: u8 = std::str::from_utf8(&digits).ok()?.parse().ok()?;
+
+                        token.push(value);
+                    } else {
+                        token.push(escaped);
+                    }
+                }
+                Some(byte) => token.push(byte),
+            }
+        }
+
+        tokens.push(String::from_utf8_lossy(&token).into_owned());
+    }
+
+    Some(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use dns_message_parser::rr::{ServiceParameter, RR};
+
+    use super::parse;
+    use crate::dns::{DnsAnswer, RecordType};
+
+    fn answer(record_type: &RecordType, data: &str) -> DnsAnswer {
+        DnsAnswer {
+            domain_name: String::from("example.com."),
+            r#type: record_type.value(),
+            ttl: 300,
+            data: String::from(data),
+        }
+    }
+
+    fn parse_data(record_type: RecordType, data: &str) -> Option<RR> {
+        parse(&record_type, &answer(&record_type, data))
+    }
+
+    #[test]
+    fn parses_address_records() {
+        assert!(matches!(
+            parse_data(RecordType::A, "93.184.216.34"),
+            Some(RR::A(_))
+        ));
+        assert!(matches!(
+            parse_data(RecordType::AAAA, "2606:2800:220:1:248:1893:25c8:1946"),
+            Some(RR::AAAA(_))
+        ));
+        assert!(parse_data(RecordType::A, "not an address").is_none());
+    }
+
+    #[test]
+    fn parses_name_records() {
+        match parse_data(RecordType::MX, "10 mail.example.com.") {
+            Some(RR::MX(mx)) => {
+                assert_eq!(mx.preference, 10);
+                assert_eq!(mx.exchange.to_string(), "mail.example.com.");
+            }
+            record => panic!("expected an MX record, got {:?}", record),
+        }
+
+        match parse_data(RecordType::SRV, "5 10 5060 sip.example.com.") {
+            Some(RR::SRV(srv)) => {
+                assert_eq!((srv.priority, srv.weight, srv.port), (5, 10, 5060));
+            }
+            record => panic!("expected an SRV record, got {:?}", record),
+        }
+
+        assert!(matches!(
+            parse_data(RecordType::CNAME, "www.example.com."),
+            Some(RR::CNAME(_))
+        ));
+        assert!(matches!(
+            parse_data(
+                RecordType::SOA,
+                "ns.example.com. hostmaster.example.com. 2023052201 7200 3600 1209600 3600"
+            ),
+            Some(RR::SOA(soa)) if soa.min_ttl == 3600
+        ));
+    }
+
+    #[test]
+    fn parses_quoted_strings() {
+        match parse_data(RecordType::TXT, "\"v=spf1 -all\" \"say \\\"hi\\\"\"") {
+            Some(RR::TXT(txt)) => {
+                let strings: Vec<&String> = txt.strings.iter().collect();
+
+                assert_eq!(strings, vec!["v=spf1 -all", "say \"hi\""]);
+            }
+            record => panic!("expected a TXT record, got {:?}", record),
+        }
+
+        match parse_data(RecordType::CAA, "0 issue \"letsencrypt.org\"") {
+            Some(RR::CAA(caa)) => assert_eq!(caa.value, b"letsencrypt.org"),
+            record => panic!("expected a CAA record, got {:?}", record),
+        }
+
+        assert!(parse_data(RecordType::TXT, "\"unterminated").is_none());
+    }
+
+    #[test]
+    fn parses_dnssec_records() {
+        assert!(matches!(
+            parse_data(
+                RecordType::DS,
+                "2371 13 2 1F987CC6583E92DF0890718C42 15DA1F2D4A4D1E6F3C0B0C0D4E5F6A7B"
+            ),
+            Some(RR::DS(_))
+        ));
+        assert!(matches!(
+            parse_data(
+                RecordType::DNSKEY,
+                "257 3 13 mdsswUyr3DPW132mOi8V9xESWE8jTo0dxCjjnopKl+GqJxpVXckHAeF+KkxLbxILfDLUT0rAK9iUzy1L53eKGQ=="
+            ),
+            Some(RR::DNSKEY(dnskey)) if dnskey.secure_entry_point_flag
+        ));
+    }
+
+    #[test]
+    fn parses_service_bindings() {
+        match parse_data(
+            RecordType::HTTPS,
+            "1 . alpn=h3,h2 port=443 ipv4hint=104.16.132.229,104.16.133.229",
+        ) {
+            Some(RR::HTTPS(https)) => {
+                assert_eq!(https.priority, 1);
+                assert!(https.target_name.is_root());
+                assert!(https
+                    .parameters
+                    .contains(&ServiceParameter::PORT { port: 443 }));
+                assert_eq!(https.parameters.len(), 3);
+            }
+            record => panic!("expected an HTTPS record, got {:?}", record),
+        }
+    }
+
+    #[test]
+    fn parses_generic_format() {
+        // `1 . alpn=h2` in the generic format
+        let data = "\\# 13 00 01 00 00 01 00 03 02 68 32 00 00 00";
+
+        assert!(parse_data(RecordType::HTTPS, data).is_none());
+
+        let data = "\\# 10 00 01 00 00 01 00 03 02 68 32";
+
+        assert!(matches!(
+            parse_data(RecordType::HTTPS, data),
+            Some(RR::HTTPS(_))
+        ));
+    }
+}
@@ -0,0 +1,18 @@
+//! Noticing when we're asked to stop, so there's a chance to clean up before exiting.
+
+use std::io;
+
+use tokio::signal::{
+    ctrl_c,
+    unix::{signal, SignalKind},
+};
+
+/// Waits until we receive SIGTERM (e.g. from systemd) or SIGINT (Ctrl+C).
+pub async fn requested() -> io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = terminate.recv() => Ok(()),
+        result = ctrl_c() => result,
+    }
+}
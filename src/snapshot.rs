@@ -0,0 +1,264 @@
+//! Keeping the cache across restarts, by writing it to a file.
+//!
+//! The file starts with [`MAGIC`] and the [`VERSION`] of its format, followed by the entries from
+//! the least to the most recently used one. Files in any other version are ignored, so an upgrade
+//! starts with an empty cache instead of loading entries it can't make sense of.
+
+use std::{
+    error::Error,
+    fs,
+    io::{self, Cursor, ErrorKind, Read},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use tokio::{task, time::Duration};
+
+use crate::{
+    cache::{Cache, CacheEntry},
+    config::{self, SwiftConfig},
+    dns,
+};
+
+const MAGIC: &[u8; 8] = b"SWIFTDNS";
+
+/// Bumped whenever the format changes.
+const VERSION: u32 = 2;
+
+#[derive(Clone)]
+pub struct Snapshot {
+    path: PathBuf,
+    /// How often the cache is saved while running
+    pub interval: Duration,
+}
+
+impl Snapshot {
+    /// Returns where to keep the cache, or `None` if it isn't kept across restarts.
+    pub fn from_config(config: &SwiftConfig) -> Option<Snapshot> {
+        let path = config.cache_file.as_ref()?;
+
+        Some(Snapshot {
+            path: config::config_location().join(path),
+            interval: Duration::from_secs(config.cache_save_interval.max(1)),
+        })
+    }
+
+    /// Fills the cache with the saved entries that haven't expired yet. If there are none, or they
+    /// can't be read, the cache stays empty.
+    pub fn load(&self, cache: &mut Cache) {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return,
+            Err(err) => {
+                warn!(
+                    "failed to read cache file `{}` ({})",
+                    self.path.display(),
+                    err
+                );
+
+                return;
+            }
+        };
+
+        match decode(&bytes) {
+            Ok(entries) => {
+                for (question, entry) in entries {
+                    cache.insert(question, entry);
+                }
+
+                let expired = cache.remove_expired();
+
+                info!(
+                    "loaded {} cached responses from `{}` ({} had expired)",
+                    cache.len(),
+                    self.path.display(),
+                    expired
+                );
+            }
+            Err(err) => warn!("ignoring cache file `{}` ({})", self.path.display(), err),
+        }
+    }
+
+    /// Writes the cache to the file, replacing what was saved before.
+    pub async fn save(&self, cache: &Mutex<Cache>) {
+        // The lock is only held while the entries are copied, not while the file is written
+        let (bytes, count) = encode(&cache.lock().unwrap());
+        let path = self.path.clone();
+
+        match task::spawn_blocking(move || write(&path, &bytes)).await {
+            Ok(Ok(())) => debug!(
+                "saved {} cached responses to `{}`",
+                count,
+                self.path.display()
+            ),
+            Ok(Err(err)) => warn!(
+                "failed to save the cache to `{}` ({})",
+                self.path.display(),
+                err
+            ),
+            Err(err) => warn!("failed to save the cache ({})", err),
+        }
+    }
+}
+
+/// Writes to a temporary file first and moves it into place, so a crash halfway through doesn't
+/// leave a truncated file behind.
+fn write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temporary = path.with_extension("tmp");
+
+    fs::write(&temporary, bytes)?;
+    fs::rename(&temporary, path)
+}
+
+/// Returns the cache in the snapshot format, along with the number of entries in it.
+fn encode(cache: &Cache) -> (Vec<u8>, usize) {
+    let mut bytes = Vec::new();
+    let mut count = 0;
+
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_be_bytes());
+
+    for (question, entry) in cache.entries() {
+        // Neither can be longer than this in a valid message
+        let (Ok(name_length), Ok(response_length)) = (
+            u8::try_from(question.name.len()),
+            u16::try_from(entry.response.len()),
+        ) else {
+            continue;
+        };
+
+        bytes.push(name_length);
+        bytes.extend_from_slice(question.name.as_bytes());
+        bytes.extend_from_slice(&question.r#type.to_be_bytes());
+        bytes.extend_from_slice(&question.class.to_be_bytes());
+        bytes.push(question.dnssec_ok.into());
+        bytes.extend_from_slice(&entry.stored_at.timestamp_millis().to_be_bytes());
+        bytes.extend_from_slice(&entry.valid_until.timestamp_millis().to_be_bytes());
+        bytes.extend_from_slice(&response_length.to_be_bytes());
+        bytes.extend_from_slice(&entry.response);
+
+        count += 1;
+    }
+
+    (bytes, count)
+}
+
+fn decode(bytes: &[u8]) -> Result<Vec<(dns::DnsQuestion, CacheEntry)>, Box<dyn Error>> {
+    let mut reader = Cursor::new(bytes);
+
+    if &read_array::<8>(&mut reader)? != MAGIC {
+        return Err("not a cache file".into());
+    }
+
+    let version = u32::from_be_bytes(read_array(&mut reader)?);
+
+    if version != VERSION {
+        return Err(format!("unsupported version {}", version).into());
+    }
+
+    let mut entries = Vec::new();
+
+    while (reader.position() as usize) < bytes.len() {
+        let [name_length] = read_array(&mut reader)?;
+        let name = String::from_utf8(read_vec(&mut reader, name_length.into())?)?;
+        let r#type = u16::from_be_bytes(read_array(&mut reader)?);
+        let class = u16::from_be_bytes(read_array(&mut reader)?);
+        let [dnssec_ok] = read_array(&mut reader)?;
+        let stored_at = read_time(&mut reader)?;
+        let valid_until = read_time(&mut reader)?;
+        let response_length = u16::from_be_bytes(read_array(&mut reader)?);
+        let response = read_vec(&mut reader, response_length.into())?;
+
+        let question = dns::DnsQuestion {
+            name,
+            r#type,
+            class,
+            dnssec_ok: dnssec_ok != 0,
+        };
+
+        entries.push((question, CacheEntry::new(stored_at, valid_until, response)));
+    }
+
+    Ok(entries)
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+fn read_vec(reader: &mut impl Read, length: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; length];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+fn read_time(reader: &mut impl Read) -> Result<DateTime<Utc>, Box<dyn Error>> {
+    let millis = i64::from_be_bytes(read_array(reader)?);
+
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .ok_or_else(|| format!("invalid timestamp {}", millis).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use super::{decode, encode, VERSION};
+    use crate::{
+        cache::{Cache, CacheEntry},
+        config::SwiftConfig,
+        dns::DnsQuestion,
+    };
+
+    fn question(name: &str) -> DnsQuestion {
+        DnsQuestion {
+            name: name.to_owned(),
+            r#type: 28,
+            class: 1,
+            dnssec_ok: true,
+        }
+    }
+
+    #[test]
+    fn keeps_entries_in_order() {
+        let mut cache = Cache::new(&SwiftConfig::default());
+        let now = Utc::now();
+
+        for (name, response) in [("a.example", vec![1, 2, 3]), ("b.example", vec![4, 5])] {
+            let entry = CacheEntry::new(now, now + Duration::seconds(300), response);
+            cache.insert(question(name), entry);
+        }
+
+        let (bytes, count) = encode(&cache);
+        let entries = decode(&bytes).unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0].0, question("a.example"));
+        assert_eq!(entries[0].1.response, vec![1, 2, 3]);
+        assert_eq!(
+            entries[0].1.valid_until.timestamp_millis(),
+            (now + Duration::seconds(300)).timestamp_millis()
+        );
+        assert_eq!(entries[1].0, question("b.example"));
+
+        // Anything cut off or from another version is rejected as a whole
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut bytes = bytes;
+        bytes[8..12].copy_from_slice(&(VERSION + 1).to_be_bytes());
+
+        assert!(decode(&bytes).is_err());
+    }
+}
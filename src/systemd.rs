@@ -0,0 +1,127 @@
+//! Socket activation, so systemd can bind privileged ports for us and start us on demand.
+//!
+//! The sockets are passed as file descriptors starting at 3, with their number in `LISTEN_FDS`
+//! and the names from `FileDescriptorName=` in `LISTEN_FDNAMES` (see sd_listen_fds(3)).
+
+use std::{env, error::Error, os::fd::FromRawFd, process};
+
+use socket2::{Socket, Type};
+
+use crate::config::ListenProtocol;
+
+/// The first file descriptor passed by systemd, right after stdin, stdout and stderr.
+const LISTEN_FDS_START: i32 = 3;
+
+/// A socket passed by systemd, with the protocol to serve on it.
+pub type PassedSocket = (Socket, ListenProtocol);
+
+/// Returns the sockets passed by systemd along with the protocol to serve on each of them, or
+/// `None` if we weren't socket activated.
+///
+/// Datagram sockets serve `udp`. Stream sockets serve `tcp`, unless the socket unit names them
+/// `dot` or `doh` with `FileDescriptorName=`.
+///
+/// The variables are left in the environment, since changing it isn't sound once other threads
+/// are running. Any process we start has a different PID, so it won't take them for its own.
+pub fn sockets() -> Result<Option<Vec<PassedSocket>>, Box<dyn Error>> {
+    let Some(count) = count(
+        env::var("LISTEN_PID").ok(),
+        env::var("LISTEN_FDS").ok(),
+        process::id(),
+    )?
+    else {
+        return Ok(None);
+    };
+
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    let mut sockets = Vec::new();
+
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd hands these file descriptors over to us, and nothing else owns them
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let protocol = protocol(socket.r#type()?, names.next())
+            .ok_or_else(|| format!("passed socket {} has an unsupported type", fd))?;
+
+        sockets.push((socket, protocol));
+    }
+
+    Ok(Some(sockets))
+}
+
+/// Returns how many sockets were passed to us, from the values of `LISTEN_PID` and `LISTEN_FDS`.
+fn count(pid: Option<String>, count: Option<String>, own_pid: u32) -> Result<Option<i32>, String> {
+    let (Some(pid), Some(count)) = (pid, count) else {
+        return Ok(None);
+    };
+
+    // They may also have been inherited from a parent that was socket activated itself
+    if pid.parse::<u32>().ok() != Some(own_pid) {
+        return Ok(None);
+    }
+
+    count
+        .parse::<i32>()
+        .ok()
+        .filter(|count| *count >= 0)
+        .map(Some)
+        .ok_or_else(|| format!("`LISTEN_FDS` is not a number of sockets ({})", count))
+}
+
+/// Returns the protocol to serve on a passed socket, from its type and name.
+fn protocol(r#type: Type, name: Option<&str>) -> Option<ListenProtocol> {
+    match (r#type, name) {
+        (Type::DGRAM, _) => Some(ListenProtocol::Udp),
+        (Type::STREAM, Some("dot")) => Some(ListenProtocol::Dot),
+        (Type::STREAM, Some("doh")) => Some(ListenProtocol::Doh),
+        (Type::STREAM, _) => Some(ListenProtocol::Tcp),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use socket2::Type;
+
+    use super::{count, protocol};
+    use crate::config::ListenProtocol;
+
+    #[test]
+    fn counts_sockets_passed_to_us() {
+        let var = |value: &str| Some(String::from(value));
+
+        assert_eq!(count(None, None, 42), Ok(None));
+        assert_eq!(count(var("42"), None, 42), Ok(None));
+        assert_eq!(count(var("42"), var("2"), 42), Ok(Some(2)));
+
+        // Meant for another process
+        assert_eq!(count(var("41"), var("2"), 42), Ok(None));
+        assert_eq!(count(var("systemd"), var("2"), 42), Ok(None));
+
+        assert!(count(var("42"), var("two"), 42).is_err());
+        assert!(count(var("42"), var("-1"), 42).is_err());
+    }
+
+    #[test]
+    fn picks_protocols_by_name() {
+        assert_eq!(protocol(Type::DGRAM, None), Some(ListenProtocol::Udp));
+        assert_eq!(
+            protocol(Type::DGRAM, Some("dot")),
+            Some(ListenProtocol::Udp)
+        );
+        assert_eq!(protocol(Type::STREAM, None), Some(ListenProtocol::Tcp));
+        assert_eq!(
+            protocol(Type::STREAM, Some("swiftdns.socket")),
+            Some(ListenProtocol::Tcp)
+        );
+        assert_eq!(
+            protocol(Type::STREAM, Some("dot")),
+            Some(ListenProtocol::Dot)
+        );
+        assert_eq!(
+            protocol(Type::STREAM, Some("doh")),
+            Some(ListenProtocol::Doh)
+        );
+        assert_eq!(protocol(Type::SEQPACKET, None), None);
+    }
+}
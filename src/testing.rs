@@ -0,0 +1,171 @@
+//! Helpers shared by the tests of several modules.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use dns_message_parser::{
+    question::{QClass, QType, Question},
+    rr::{Class, A, RR, SOA},
+    Dns, Flags, Opcode, RCode,
+};
+use rcgen::CertifiedKey;
+use rustls::RootCertStore;
+use tokio::{
+    net::UdpSocket,
+    time::{self, Duration},
+};
+
+use crate::{
+    cache::Cache,
+    client::Context,
+    config::{SwiftConfig, UpstreamConfig},
+    filter::Filter,
+    upstream::Forwarder,
+    wire,
+};
+
+/// The queries an upstream has received.
+pub type Received = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// Starts an upstream that answers every query with an empty response after `delay`, and returns
+/// a context that forwards to it.
+pub async fn context(delay: Duration) -> (Arc<Context>, Received) {
+    let (address, received) = upstream(delay, wire::RCODE_NO_ERROR).await;
+
+    let config = SwiftConfig {
+        upstreams: vec![UpstreamConfig {
+            name: None,
+            url: format!("udp://{}", address),
+            bootstrap: None,
+            sni: None,
+            pin: None,
+            protocol: None,
+        }],
+        ..SwiftConfig::default()
+    };
+
+    let context = Context::new(
+        Forwarder::from_config(&config).unwrap(),
+        Filter::default(),
+        Cache::new(&config),
+    );
+
+    (Arc::new(context), received)
+}
+
+/// Starts a plain DNS upstream that answers every query with an empty response with `rcode`
+/// after `delay`, and returns its address.
+pub async fn upstream(delay: Duration, rcode: u8) -> (SocketAddr, Received) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap();
+    let received = Received::default();
+
+    let queries = received.clone();
+
+    tokio::spawn(async move {
+        let mut buf = [0; 4096];
+
+        loop {
+            let (length, src) = socket.recv_from(&mut buf).await.unwrap();
+            let mut response = buf[..length].to_vec();
+
+            queries.lock().unwrap().push(response.clone());
+
+            response[2] |= 0x80;
+            response[3] = 0x80 | rcode;
+
+            time::sleep(delay).await;
+            socket.send_to(&response, src).await.unwrap();
+        }
+    });
+
+    (address, received)
+}
+
+/// Builds a query from a client, with an OPT record if `opt` has one (the flags and options).
+pub fn query(name: &str, r#type: u16, opt: Option<(u16, &[u8])>) -> Vec<u8> {
+    let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    message.extend_from_slice(&wire::Question::new(name, r#type).unwrap().encoded);
+
+    if let Some((flags, options)) = opt {
+        message[11] = 1;
+
+        message.extend_from_slice(&[0, 0, 41, 0x04, 0xd0, 0, 0]);
+        message.extend_from_slice(&flags.to_be_bytes());
+        message.extend_from_slice(&(options.len() as u16).to_be_bytes());
+        message.extend_from_slice(options);
+    }
+
+    message
+}
+
+/// Builds a response from an upstream to an `A` question for `name`.
+pub fn message(name: &str, rcode: RCode, answers: Vec<RR>, authorities: Vec<RR>) -> Vec<u8> {
+    let dns = Dns {
+        id: 0,
+        flags: Flags {
+            qr: true,
+            opcode: Opcode::Query,
+            aa: false,
+            tc: false,
+            rd: true,
+            ra: true,
+            ad: false,
+            cd: false,
+            rcode,
+        },
+        questions: vec![Question {
+            domain_name: name.parse().unwrap(),
+            q_class: QClass::IN,
+            q_type: QType::A,
+        }],
+        answers,
+        authorities,
+        additionals: Vec::new(),
+    };
+
+    dns.encode().unwrap().to_vec()
+}
+
+pub fn a(name: &str, ttl: u32) -> RR {
+    RR::A(A {
+        domain_name: name.parse().unwrap(),
+        ttl,
+        ipv4_addr: "93.184.216.34".parse().unwrap(),
+    })
+}
+
+/// The SOA record of the `example` zone, as found in the authority section of negative responses.
+pub fn soa(ttl: u32, min_ttl: u32) -> RR {
+    RR::SOA(SOA {
+        domain_name: "example".parse().unwrap(),
+        ttl,
+        class: Class::IN,
+        m_name: "ns.example".parse().unwrap(),
+        r_name: "hostmaster.example".parse().unwrap(),
+        serial: 1,
+        refresh: 7200,
+        retry: 3600,
+        expire: 1209600,
+        min_ttl,
+    })
+}
+
+/// The name the certificates from [`certificate`] are made out to.
+pub const SERVER_NAME: &str = "dns.example";
+
+/// Makes a self-signed certificate for [`SERVER_NAME`].
+pub fn certificate() -> CertifiedKey {
+    rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()]).unwrap()
+}
+
+/// Returns a root store that only trusts the certificate.
+pub fn roots(certified: &CertifiedKey) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+
+    roots
+}
@@ -0,0 +1,143 @@
+//! The certificate and key for the listeners that serve clients over TLS.
+
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use rustls::ServerConfig;
+use tokio::time::{self, Duration};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::{self, SwiftConfig};
+
+/// How often the certificate and key are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Accepts TLS connections with the configured certificate and key, and picks up a renewed
+/// certificate (e.g. by certbot) without having to restart.
+pub struct Acceptor {
+    certificate_path: PathBuf,
+    key_path: PathBuf,
+    alpn: Vec<Vec<u8>>,
+    current: RwLock<Loaded>,
+}
+
+struct Loaded {
+    acceptor: TlsAcceptor,
+    /// When the certificate and key were last modified, to tell when they change
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+/// Returns the path of a file from the configuration, relative paths are relative to the
+/// configuration directory.
+fn config_path(path: &Path) -> PathBuf {
+    config::config_location().join(path)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+impl Acceptor {
+    /// Loads the configured certificate chain and private key, for TLS connections that
+    /// negotiate one of the `alpn` protocols.
+    pub fn new(config: &SwiftConfig, alpn: &[&[u8]]) -> Result<Arc<Acceptor>, Box<dyn Error>> {
+        let certificate_path = config_path(&config.tls_certificate);
+        let key_path = config_path(&config.tls_key);
+        let alpn: Vec<Vec<u8>> = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+        let modified = (modified(&certificate_path), modified(&key_path));
+        let acceptor = load(&certificate_path, &key_path, &alpn)?;
+
+        Ok(Arc::new(Acceptor {
+            certificate_path,
+            key_path,
+            alpn,
+            current: RwLock::new(Loaded { acceptor, modified }),
+        }))
+    }
+
+    /// Returns the acceptor for the certificate and key as they currently are.
+    pub fn get(&self) -> TlsAcceptor {
+        self.current.read().unwrap().acceptor.clone()
+    }
+
+    /// Checks the certificate and key for changes every so often, for as long as the listener runs.
+    pub async fn watch(self: Arc<Self>) {
+        let mut interval = time::interval(RELOAD_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            self.reload();
+        }
+    }
+
+    /// Reloads the certificate and key if either of them changed since they were last loaded. If
+    /// they can't be loaded, the previous ones stay in use.
+    pub fn reload(&self) {
+        let modified = (modified(&self.certificate_path), modified(&self.key_path));
+
+        if modified == self.current.read().unwrap().modified {
+            return;
+        }
+
+        match load(&self.certificate_path, &self.key_path, &self.alpn) {
+            Ok(acceptor) => {
+                info!(
+                    "reloaded tls certificate `{}`",
+                    self.certificate_path.display()
+                );
+
+                *self.current.write().unwrap() = Loaded { acceptor, modified };
+            }
+            Err(err) => {
+                warn!("failed to reload tls certificate ({})", err);
+
+                // Only retried once either file changes again, e.g. when the key is written
+                // after the certificate
+                self.current.write().unwrap().modified = modified;
+            }
+        }
+    }
+}
+
+fn load(
+    certificate_path: &Path,
+    key_path: &Path,
+    alpn: &[Vec<u8>],
+) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| format!("failed to open `{}` ({})", path.display(), err))
+    };
+
+    let certificates =
+        rustls_pemfile::certs(&mut open(certificate_path)?).collect::<Result<Vec<_>, _>>()?;
+
+    if certificates.is_empty() {
+        return Err(format!("no certificates found in `{}`", certificate_path.display()).into());
+    }
+
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)?
+        .ok_or_else(|| format!("no private key found in `{}`", key_path.display()))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let mut server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+
+    server_config.alpn_protocols = alpn.to_vec();
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
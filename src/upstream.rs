@@ -0,0 +1,958 @@
+//! The servers queries are forwarded to.
+
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::future;
+use reqwest::{Client, ClientBuilder, Proxy, Url};
+use tokio::time;
+
+use crate::{
+    config::{Protocol, Strategy, SwiftConfig, UpstreamConfig},
+    dns::{self, ResolveError},
+    domain::Domain,
+    dot::TlsUpstream,
+    filter::Rules,
+    plain, wire,
+};
+
+/// The SOCKS proxy of the local Tor daemon. Host names are resolved by the proxy.
+const TOR_PROXY: &str = "socks5h://127.0.0.1:9050";
+
+/// How many failures in a row it takes before an upstream is taken out of rotation.
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// How long an upstream is left alone the first time it goes down. Doubles every time it fails
+/// again after coming back, up to `MAX_BACKOFF`.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// The weight of the latest request in the average latency of an upstream.
+const LATENCY_WEIGHT: f64 = 0.3;
+
+/// Returns a client builder that routes every request through Tor if `tor` is enabled.
+pub fn client_builder(tor: bool) -> ClientBuilder {
+    let builder = Client::builder();
+
+    if tor {
+        let proxy = Proxy::all(TOR_PROXY).expect("Could not find tor proxy at 127.0.0.1:9050");
+
+        builder.proxy(proxy)
+    } else {
+        builder
+    }
+}
+
+/// Tracks how well an upstream is doing, as a circuit breaker.
+///
+/// Only failing to reach the upstream (errors from the connection or HTTP, or timeouts) counts as a
+/// failure. After `FAILURE_THRESHOLD` of them in a row the upstream is considered down, and isn't used
+/// until its backoff has passed. The next query then tries it again: a success brings it back,
+/// a failure takes it down again for twice as long.
+#[derive(Debug)]
+struct Health {
+    failures: u32,
+    /// An exponentially weighted moving average of the response time
+    latency: Option<Duration>,
+    down_until: Option<Instant>,
+    backoff: Duration,
+}
+
+impl Health {
+    fn new() -> Health {
+        Health {
+            failures: 0,
+            latency: None,
+            down_until: None,
+            backoff: MIN_BACKOFF,
+        }
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        self.down_until.is_none_or(|until| now >= until)
+    }
+
+    fn is_down(&self) -> bool {
+        self.down_until.is_some()
+    }
+
+    fn record_success(&mut self, latency: Duration) {
+        self.failures = 0;
+        self.down_until = None;
+        self.backoff = MIN_BACKOFF;
+
+        self.latency = Some(match self.latency {
+            Some(average) => {
+                average.mul_f64(1.0 - LATENCY_WEIGHT) + latency.mul_f64(LATENCY_WEIGHT)
+            }
+            None => latency,
+        });
+    }
+
+    /// Returns how long the upstream is taken out of rotation, if this failure takes it down.
+    fn record_failure(&mut self, now: Instant) -> Option<Duration> {
+        self.failures += 1;
+
+        if self.failures < FAILURE_THRESHOLD {
+            return None;
+        }
+
+        let backoff = self.backoff;
+
+        self.down_until = Some(now + backoff);
+        self.backoff = (backoff * 2).min(MAX_BACKOFF);
+
+        Some(backoff)
+    }
+}
+
+/// How queries reach an upstream.
+enum Transport {
+    /// DNS over HTTPS, with the SNI name as the host of the URL
+    Https {
+        url: Url,
+        protocol: Protocol,
+        client: Client,
+    },
+    /// Classic DNS over UDP, falling back to TCP for truncated responses
+    Udp(SocketAddr),
+    /// Classic DNS over TCP
+    Tcp(SocketAddr),
+    /// DNS over TLS
+    Tls(TlsUpstream),
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Https { protocol, .. } => write!(f, "{:?}", protocol),
+            Transport::Udp(address) => write!(f, "udp {}", address),
+            Transport::Tcp(address) => write!(f, "tcp {}", address),
+            Transport::Tls(upstream) => write!(f, "tls {}", upstream),
+        }
+    }
+}
+
+/// Returns the host of the URL, without the brackets around IPv6 addresses.
+fn host(url: &Url) -> Result<String, Box<dyn Error>> {
+    match url.host_str() {
+        Some(host) => Ok(host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned()),
+        None => Err(format!("upstream `{}` has no host", url).into()),
+    }
+}
+
+fn https_transport(
+    config: &UpstreamConfig,
+    mut url: Url,
+    default_protocol: Protocol,
+    tor: bool,
+) -> Result<Transport, Box<dyn Error>> {
+    let host = host(&url)?;
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    // reqwest sends the host of the URL as the SNI name, so a custom one goes in the URL
+    // instead, and the connection is pointed at the original host.
+    let server_name = config.sni.clone().unwrap_or_else(|| host.clone());
+    let address = config.bootstrap.or_else(|| host.parse::<IpAddr>().ok());
+
+    if server_name != host {
+        // Without an address to point it at, the connection would go to the SNI name instead.
+        // Over tor, the proxy resolves whatever is in the URL.
+        if address.is_none() || tor {
+            return Err(format!(
+                "upstream `{}` sets `sni`, which needs a bootstrap address or an IP address in the URL (and can't be used with tor)",
+                config.url
+            )
+            .into());
+        }
+
+        url.set_host(Some(&server_name))?;
+    }
+
+    let mut builder = client_builder(tor);
+
+    match address {
+        Some(address) if server_name.parse::<IpAddr>().is_err() => {
+            builder = builder.resolve(&server_name, SocketAddr::new(address, port));
+        }
+        Some(_) => {}
+        // This works as long as swiftdns isn't the system resolver, otherwise it would ask itself
+        None if !tor => warn!(
+            "upstream `{}` has no bootstrap address, `{}` will be resolved by the system",
+            config.url, host
+        ),
+        None => {}
+    }
+
+    Ok(Transport::Https {
+        url,
+        protocol: config.protocol.unwrap_or(default_protocol),
+        client: builder.build()?,
+    })
+}
+
+/// A single upstream server, with its own connection pool.
+pub struct Upstream {
+    /// The name or URL of the upstream, to identify it in logs and forwarding rules
+    name: String,
+    transport: Transport,
+    health: Mutex<Health>,
+}
+
+impl Upstream {
+    pub fn new(
+        config: &UpstreamConfig,
+        default_protocol: Protocol,
+        tor: bool,
+    ) -> Result<Upstream, Box<dyn Error>> {
+        let url = Url::parse(&config.url)?;
+
+        if config.pin.is_some() && url.scheme() != "tls" {
+            return Err(format!(
+                "upstream `{}` has a pin, which is only supported over tls",
+                config.url
+            )
+            .into());
+        }
+
+        let transport = match url.scheme() {
+            "https" | "http" => https_transport(config, url, default_protocol, tor)?,
+            "udp" | "tcp" => {
+                // Tor only carries TCP, and plain DNS would reveal every query to the network anyway
+                if tor {
+                    return Err(format!(
+                        "plain DNS upstream `{}` can't be used with tor",
+                        config.url
+                    )
+                    .into());
+                }
+
+                let ip = match config.bootstrap {
+                    Some(ip) => ip,
+                    None => host(&url)?.parse().map_err(|_| {
+                        format!(
+                            "plain DNS upstream `{}` needs an IP address or a bootstrap address",
+                            config.url
+                        )
+                    })?,
+                };
+
+                let address = SocketAddr::new(ip, url.port().unwrap_or(53));
+
+                if url.scheme() == "udp" {
+                    Transport::Udp(address)
+                } else {
+                    Transport::Tcp(address)
+                }
+            }
+            "tls" => {
+                if tor {
+                    return Err(format!(
+                        "DNS over TLS upstream `{}` can't be used with tor",
+                        config.url
+                    )
+                    .into());
+                }
+
+                let host = host(&url)?;
+                let port = url.port().unwrap_or(853);
+
+                let address = match config.bootstrap {
+                    Some(ip) => SocketAddr::new(ip, port).to_string(),
+                    None => {
+                        if host.parse::<IpAddr>().is_err() {
+                            warn!(
+                                "upstream `{}` has no bootstrap address, `{}` will be resolved by the system",
+                                config.url, host
+                            );
+                        }
+
+                        // The host is kept as it is in the URL, with brackets around IPv6 addresses
+                        format!("{}:{}", url.host_str().unwrap_or_default(), port)
+                    }
+                };
+
+                let server_name = config.sni.as_deref().unwrap_or(&host);
+
+                Transport::Tls(TlsUpstream::new(
+                    address,
+                    server_name,
+                    config.pin.as_deref(),
+                )?)
+            }
+            scheme => {
+                return Err(format!(
+                    "upstream `{}` has unsupported scheme `{}`, expected https, tls, udp or tcp",
+                    config.url, scheme
+                )
+                .into())
+            }
+        };
+
+        Ok(Upstream {
+            name: config.name.clone().unwrap_or_else(|| config.url.clone()),
+            transport,
+            health: Mutex::new(Health::new()),
+        })
+    }
+
+    /// Resolves the question with this upstream, returning the response in wire format.
+    pub async fn resolve(
+        &self,
+        question: &wire::Question,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ResolveError> {
+        match &self.transport {
+            Transport::Https {
+                url,
+                protocol: Protocol::DohJson,
+                client,
+            } => dns::resolve_json(client, url, question, dnssec_ok).await,
+            Transport::Https {
+                url,
+                protocol,
+                client,
+            } => dns::resolve_wire(client, url, *protocol, question, dnssec_ok).await,
+            Transport::Udp(address) => plain::resolve_udp(*address, question, dnssec_ok).await,
+            Transport::Tcp(address) => plain::resolve_tcp(*address, question, dnssec_ok).await,
+            Transport::Tls(upstream) => upstream.resolve(question, dnssec_ok).await,
+        }
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        self.health.lock().unwrap().is_available(now)
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.health.lock().unwrap().latency
+    }
+
+    fn record_success(&self, latency: Duration) {
+        let mut health = self.health.lock().unwrap();
+        let was_down = health.is_down();
+
+        health.record_success(latency);
+
+        if was_down {
+            info!("upstream `{}` is back up ({}ms)", self, latency.as_millis());
+        }
+    }
+
+    fn record_failure(&self, err: &ResolveError) {
+        let mut health = self.health.lock().unwrap();
+
+        debug!(
+            "upstream `{}` failed ({} in a row): {}",
+            self,
+            health.failures + 1,
+            err
+        );
+
+        if let Some(backoff) = health.record_failure(Instant::now()) {
+            warn!(
+                "upstream `{}` is down after {} failures in a row, retrying in {}s (average latency: {})",
+                self,
+                health.failures,
+                backoff.as_secs(),
+                match health.latency {
+                    Some(latency) => format!("{}ms", latency.as_millis()),
+                    None => String::from("unknown"),
+                }
+            );
+        }
+    }
+}
+
+impl Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// A group of upstreams that share the load of the queries sent to it, in order of preference.
+pub struct Upstreams {
+    upstreams: Vec<Arc<Upstream>>,
+    strategy: Strategy,
+    /// How many upstreams are queried at once with `Strategy::Race`
+    race_count: usize,
+    /// How long to wait for a response before moving on to the next upstream
+    timeout: Duration,
+    /// The upstream to start with next, for `Strategy::RoundRobin`
+    next: AtomicUsize,
+}
+
+impl Upstreams {
+    fn new(upstreams: Vec<Arc<Upstream>>, config: &SwiftConfig) -> Upstreams {
+        Upstreams {
+            upstreams,
+            strategy: config.strategy,
+            race_count: config.race_count.max(1),
+            timeout: Duration::from_millis(config.upstream_timeout),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the upstreams that are up, in the order they should be tried in.
+    fn candidates(&self) -> Vec<&Upstream> {
+        let now = Instant::now();
+
+        let mut candidates: Vec<&Upstream> = match self.strategy {
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % self.upstreams.len();
+
+                self.upstreams[start..]
+                    .iter()
+                    .chain(&self.upstreams[..start])
+                    .map(AsRef::as_ref)
+                    .collect()
+            }
+            _ => self.upstreams.iter().map(AsRef::as_ref).collect(),
+        };
+
+        if self.strategy == Strategy::Fastest {
+            // Upstreams that haven't answered yet go first, so they get a latency to compare
+            candidates.sort_by_key(|upstream| upstream.latency().unwrap_or(Duration::ZERO));
+        }
+
+        let available: Vec<&Upstream> = candidates
+            .iter()
+            .copied()
+            .filter(|upstream| upstream.is_available(now))
+            .collect();
+
+        // Trying an upstream that is down beats not answering at all
+        if available.is_empty() {
+            candidates
+        } else {
+            available
+        }
+    }
+
+    /// Resolves the question with the upstreams picked by the strategy, moving on to the next
+    /// one whenever an upstream fails or doesn't respond in time.
+    pub async fn resolve(
+        &self,
+        question: &wire::Question,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ResolveError> {
+        let candidates = self.candidates();
+
+        let mut remaining = &candidates[..];
+        let mut last_err = None;
+        // The last SERVFAIL or REFUSED response, which beats not answering at all
+        let mut failure = None;
+
+        if self.strategy == Strategy::Race && candidates.len() > 1 {
+            let (racing, rest) = candidates.split_at(self.race_count.min(candidates.len()));
+
+            // The upstreams that lose the race are cancelled, which doesn't count against them
+            let attempts = racing
+                .iter()
+                .map(|upstream| Box::pin(self.attempt(upstream, question, dnssec_ok)));
+
+            match future::select_ok(attempts).await {
+                Ok((response, _)) => return Ok(response),
+                Err(err @ ResolveError::Unsupported(_)) => return Err(err),
+                Err(ResolveError::Failure(response)) => failure = Some(response),
+                Err(err) => last_err = Some(err),
+            }
+
+            remaining = rest;
+        }
+
+        for upstream in remaining {
+            match self.attempt(upstream, question, dnssec_ok).await {
+                Ok(response) => return Ok(response),
+                Err(err @ ResolveError::Unsupported(_)) => return Err(err),
+                Err(ResolveError::Failure(response)) => failure = Some(response),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        match failure {
+            Some(response) => Ok(response),
+            None => Err(last_err.expect("There should always be at least one upstream")),
+        }
+    }
+
+    /// Resolves the question with a single upstream, and keeps track of how it did.
+    ///
+    /// A SERVFAIL or REFUSED response is returned as an error, so that the next upstream gets a
+    /// chance to answer. It doesn't count against the upstream, which is still reachable.
+    async fn attempt(
+        &self,
+        upstream: &Upstream,
+        question: &wire::Question,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ResolveError> {
+        let start = Instant::now();
+
+        let err = match time::timeout(self.timeout, upstream.resolve(question, dnssec_ok)).await {
+            Ok(Ok(response)) => {
+                upstream.record_success(start.elapsed());
+
+                return match wire::rcode(&response) {
+                    Ok(wire::RCODE_SERVER_FAILURE | wire::RCODE_REFUSED) => {
+                        debug!("upstream `{}` couldn't resolve the query", upstream);
+
+                        Err(ResolveError::Failure(response))
+                    }
+                    _ => Ok(response),
+                };
+            }
+            // Not the fault of the upstream, the protocol just can't express the question
+            Ok(Err(err @ ResolveError::Unsupported(_))) => return Err(err),
+            // The upstream is reachable, it only sent something we can't make sense of. The next
+            // upstream is tried, but this one stays in rotation
+            Ok(Err(err @ ResolveError::InvalidResponse(_))) => {
+                debug!("upstream `{}` sent an unusable response: {}", upstream, err);
+
+                return Err(err);
+            }
+            Ok(Err(err)) => err,
+            Err(_) => ResolveError::Timeout(self.timeout),
+        };
+
+        upstream.record_failure(&err);
+
+        Err(err)
+    }
+}
+
+/// Sends queries for the domains matching a set of patterns to a specific upstream.
+struct ForwardRule {
+    domains: Rules,
+    upstreams: Upstreams,
+}
+
+/// Picks the upstreams to send each query to, based on the forwarding rules.
+pub struct Forwarder {
+    rules: Vec<ForwardRule>,
+    /// The upstreams for every query that doesn't match a forwarding rule
+    default: Upstreams,
+}
+
+impl Forwarder {
+    pub fn from_config(config: &SwiftConfig) -> Result<Forwarder, Box<dyn Error>> {
+        let mut upstreams: Vec<Arc<Upstream>> = Vec::new();
+
+        for upstream_config in &config.upstreams {
+            let upstream = Upstream::new(upstream_config, config.protocol, config.tor)?;
+
+            if upstreams.iter().any(|other| other.name == upstream.name) {
+                return Err(format!("there is more than one upstream named `{}`", upstream).into());
+            }
+
+            debug!("using upstream `{}` ({})", upstream, upstream.transport);
+
+            upstreams.push(Arc::new(upstream));
+        }
+
+        let mut rules = Vec::new();
+
+        for rule in &config.forward {
+            let upstream = upstreams
+                .iter()
+                .find(|upstream| upstream.name == rule.upstream)
+                .ok_or_else(|| {
+                    format!(
+                        "forwarding rule refers to unknown upstream `{}`",
+                        rule.upstream
+                    )
+                })?;
+
+            // The patterns are lowercased as they're compiled, just like the names in queries
+            rules.push(ForwardRule {
+                domains: Rules::from_patterns(&rule.upstream, &rule.domains),
+                upstreams: Upstreams::new(vec![upstream.clone()], config),
+            });
+        }
+
+        // Upstreams that queries are forwarded to only get the queries for those domains, so an
+        // internal resolver doesn't see every other query as well.
+        let mut default: Vec<Arc<Upstream>> = upstreams
+            .into_iter()
+            .filter(|upstream| {
+                !config
+                    .forward
+                    .iter()
+                    .any(|rule| rule.upstream == upstream.name)
+            })
+            .collect();
+
+        if default.is_empty() {
+            let preset = Upstream::new(&config.mode.upstream(), config.protocol, config.tor)?;
+
+            debug!("using upstream `{}` ({})", preset, preset.transport);
+
+            default.push(Arc::new(preset));
+        }
+
+        Ok(Forwarder {
+            rules,
+            default: Upstreams::new(default, config),
+        })
+    }
+
+    /// Returns the upstreams to send queries for the domain to.
+    fn upstreams(&self, name: &str) -> &Upstreams {
+        for rule in &self.rules {
+            if let Some(entry) = rule.domains.find(name) {
+                debug!(
+                    "forwarding `{}` to `{}` (pattern `{}`)",
+                    name, rule.upstreams.upstreams[0], entry.pattern
+                );
+
+                return &rule.upstreams;
+            }
+        }
+
+        &self.default
+    }
+
+    pub async fn resolve(
+        &self,
+        question: &wire::Question,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ResolveError> {
+        let domain = Domain::from(question.name.as_str());
+
+        self.upstreams(&domain.name)
+            .resolve(question, dnssec_ok)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::AtomicUsize, Arc},
+        time::{Duration, Instant},
+    };
+
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use crate::{
+        config::{ForwardConfig, Protocol, Strategy, SwiftConfig, UpstreamConfig},
+        dns::ResolveError,
+        domain::Domain,
+        testing, wire,
+    };
+
+    use super::{
+        Forwarder, Health, Transport, Upstream, Upstreams, FAILURE_THRESHOLD, MIN_BACKOFF,
+    };
+
+    fn config(url: &str) -> UpstreamConfig {
+        UpstreamConfig {
+            name: None,
+            url: String::from(url),
+            bootstrap: None,
+            sni: None,
+            pin: None,
+            protocol: None,
+        }
+    }
+
+    fn upstreams(strategy: Strategy) -> Upstreams {
+        let upstreams = ["https://1.1.1.1/dns-query", "https://9.9.9.9/dns-query"]
+            .iter()
+            .map(|url| Arc::new(Upstream::new(&config(url), Protocol::DohPost, false).unwrap()))
+            .collect();
+
+        Upstreams {
+            upstreams,
+            strategy,
+            race_count: 2,
+            timeout: Duration::from_secs(1),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn names(candidates: Vec<&Upstream>) -> Vec<String> {
+        candidates
+            .iter()
+            .map(|upstream| upstream.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn orders_candidates_by_strategy() {
+        let round_robin = upstreams(Strategy::RoundRobin);
+
+        assert_eq!(
+            names(round_robin.candidates()),
+            ["https://1.1.1.1/dns-query", "https://9.9.9.9/dns-query"]
+        );
+        assert_eq!(
+            names(round_robin.candidates()),
+            ["https://9.9.9.9/dns-query", "https://1.1.1.1/dns-query"]
+        );
+
+        let fastest = upstreams(Strategy::Fastest);
+
+        fastest.upstreams[0].record_success(Duration::from_millis(100));
+        fastest.upstreams[1].record_success(Duration::from_millis(10));
+
+        assert_eq!(
+            names(fastest.candidates()),
+            ["https://9.9.9.9/dns-query", "https://1.1.1.1/dns-query"]
+        );
+    }
+
+    #[test]
+    fn sends_sni_name_in_url() {
+        let upstream = Upstream::new(
+            &UpstreamConfig {
+                sni: Some(String::from("dns.quad9.net")),
+                ..config("https://9.9.9.9/dns-query")
+            },
+            Protocol::DohPost,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(upstream.to_string(), "https://9.9.9.9/dns-query");
+
+        match upstream.transport {
+            Transport::Https { url, protocol, .. } => {
+                assert_eq!(url.as_str(), "https://dns.quad9.net/dns-query");
+                assert_eq!(protocol, Protocol::DohPost);
+            }
+            _ => panic!("expected an https upstream"),
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_to_the_host_when_sending_sni() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let question = wire::Question::new("example.com", 1).unwrap();
+
+        let configs = [
+            UpstreamConfig {
+                sni: Some(String::from("dns.example")),
+                ..config(&format!("https://127.0.0.1:{}/dns-query", port))
+            },
+            UpstreamConfig {
+                sni: Some(String::from("dns.example")),
+                bootstrap: Some("127.0.0.1".parse().unwrap()),
+                ..config(&format!("https://doh.example:{}/dns-query", port))
+            },
+        ];
+
+        for config in configs {
+            let upstream = Upstream::new(&config, Protocol::DohPost, false).unwrap();
+
+            // The handshake never finishes, it only matters where the connection goes
+            tokio::select! {
+                result = upstream.resolve(&question, false) => {
+                    panic!("expected a connection to the listener, got {:?}", result)
+                }
+                accepted = listener.accept() => assert!(accepted.is_ok()),
+            }
+        }
+
+        // There would be nothing to connect to but the SNI name
+        let unresolved = UpstreamConfig {
+            sni: Some(String::from("dns.example")),
+            ..config("https://doh.example/dns-query")
+        };
+
+        assert!(Upstream::new(&unresolved, Protocol::DohPost, false).is_err());
+
+        let over_tor = UpstreamConfig {
+            sni: Some(String::from("dns.example")),
+            ..config("https://9.9.9.9/dns-query")
+        };
+
+        assert!(Upstream::new(&over_tor, Protocol::DohPost, true).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_urls() {
+        assert!(Upstream::new(&config("dns.quad9.net"), Protocol::DohPost, false).is_err());
+        assert!(Upstream::new(&config("ftp://9.9.9.9"), Protocol::DohPost, false).is_err());
+        assert!(
+            Upstream::new(&config("udp://dns.corp.example"), Protocol::DohPost, false).is_err()
+        );
+        assert!(Upstream::new(&config("udp://10.0.0.53"), Protocol::DohPost, true).is_err());
+
+        let pinned = |url: &str, pin: &str| UpstreamConfig {
+            pin: Some(String::from(pin)),
+            ..config(url)
+        };
+
+        let pin = "YZPgTZ+woNCCCIW3LH2CxQeLzB/1m42QcCTBSdgayjs=";
+
+        assert!(Upstream::new(&pinned("tls://9.9.9.9", pin), Protocol::DohPost, false).is_ok());
+        assert!(Upstream::new(
+            &pinned("tls://9.9.9.9", "c2hvcnQ="),
+            Protocol::DohPost,
+            false
+        )
+        .is_err());
+        assert!(Upstream::new(&pinned("https://9.9.9.9", pin), Protocol::DohPost, false).is_err());
+    }
+
+    #[test]
+    fn forwards_matching_domains() {
+        let config = SwiftConfig {
+            upstreams: vec![UpstreamConfig {
+                name: Some(String::from("corp")),
+                ..config("udp://10.0.0.53")
+            }],
+            forward: vec![ForwardConfig {
+                domains: vec![String::from("**.corp.example")],
+                upstream: String::from("corp"),
+            }],
+            ..SwiftConfig::default()
+        };
+
+        let forwarder = Forwarder::from_config(&config).unwrap();
+
+        let upstream = |name: &str| forwarder.upstreams(name).upstreams[0].to_string();
+
+        assert_eq!(upstream("corp.example"), "corp");
+        assert_eq!(upstream("wiki.corp.example"), "corp");
+        assert_eq!(upstream("example.com"), "https://1.1.1.1/dns-query");
+    }
+
+    #[test]
+    fn forwards_domains_regardless_of_case() {
+        let config = SwiftConfig {
+            upstreams: vec![UpstreamConfig {
+                name: Some(String::from("corp")),
+                ..config("udp://10.0.0.53")
+            }],
+            forward: vec![ForwardConfig {
+                domains: vec![String::from("**.Corp.EXAMPLE"), String::from("Wiki.Lan")],
+                upstream: String::from("corp"),
+            }],
+            ..SwiftConfig::default()
+        };
+
+        let forwarder = Forwarder::from_config(&config).unwrap();
+
+        let upstream = |name: &str| {
+            let domain = Domain::from(name);
+
+            forwarder.upstreams(&domain.name).upstreams[0].to_string()
+        };
+
+        assert_eq!(upstream("corp.example"), "corp");
+        assert_eq!(upstream("MAIL.CoRp.ExAmPlE."), "corp");
+        assert_eq!(upstream("wiki.lan"), "corp");
+        assert_eq!(upstream("WIKI.LAN"), "corp");
+        assert_eq!(upstream("example.com"), "https://1.1.1.1/dns-query");
+    }
+
+    #[tokio::test]
+    async fn moves_on_from_upstreams_that_fail_to_resolve() {
+        let (broken, _) = testing::upstream(Duration::ZERO, wire::RCODE_SERVER_FAILURE).await;
+        let (healthy, _) =
+            testing::upstream(Duration::from_millis(100), wire::RCODE_NO_ERROR).await;
+
+        let question = wire::Question::new("example.com", 1).unwrap();
+        let upstream = |address| {
+            let url = format!("udp://{}", address);
+
+            Arc::new(Upstream::new(&config(&url), Protocol::DohPost, false).unwrap())
+        };
+
+        for strategy in [Strategy::Race, Strategy::Failover] {
+            let config = SwiftConfig {
+                strategy,
+                ..SwiftConfig::default()
+            };
+
+            let upstreams = Upstreams::new(vec![upstream(broken), upstream(healthy)], &config);
+            let response = upstreams.resolve(&question, false).await.unwrap();
+
+            assert_eq!(wire::rcode(&response), Ok(wire::RCODE_NO_ERROR));
+
+            // Answering at all means the upstream is up
+            assert_eq!(upstreams.upstreams[0].health.lock().unwrap().failures, 0);
+        }
+
+        // The failure is only relayed once there's no other upstream left to try
+        let upstreams = Upstreams::new(vec![upstream(broken)], &SwiftConfig::default());
+        let response = upstreams.resolve(&question, false).await.unwrap();
+
+        assert_eq!(wire::rcode(&response), Ok(wire::RCODE_SERVER_FAILURE));
+    }
+
+    #[tokio::test]
+    async fn keeps_upstreams_with_unusable_responses_up() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+
+        // Claims an answer that isn't there
+        tokio::spawn(async move {
+            let mut buf = [0; 4096];
+
+            loop {
+                let (length, src) = socket.recv_from(&mut buf).await.unwrap();
+                let mut response = buf[..length].to_vec();
+
+                response[2] |= 0x80;
+                response[7] = 1;
+
+                socket.send_to(&response, src).await.unwrap();
+            }
+        });
+
+        let url = format!("udp://{}", address);
+        let upstream = Arc::new(Upstream::new(&config(&url), Protocol::DohPost, false).unwrap());
+        let upstreams = Upstreams::new(vec![upstream.clone()], &SwiftConfig::default());
+        let question = wire::Question::new("example.com", 1).unwrap();
+
+        for _ in 0..FAILURE_THRESHOLD + 1 {
+            assert!(matches!(
+                upstreams.resolve(&question, false).await,
+                Err(ResolveError::InvalidResponse(_))
+            ));
+        }
+
+        let health = upstream.health.lock().unwrap();
+
+        assert_eq!(health.failures, 0);
+        assert!(!health.is_down());
+    }
+
+    #[test]
+    fn takes_failing_upstreams_down() {
+        let mut health = Health::new();
+        let now = Instant::now();
+
+        assert_eq!(health.record_failure(now), None);
+        assert_eq!(health.record_failure(now), None);
+        assert_eq!(health.record_failure(now), Some(MIN_BACKOFF));
+
+        assert!(!health.is_available(now));
+        assert!(health.is_available(now + MIN_BACKOFF));
+
+        // Failing again after the backoff takes it down for longer
+        assert_eq!(
+            health.record_failure(now + MIN_BACKOFF),
+            Some(MIN_BACKOFF * 2)
+        );
+
+        health.record_success(Duration::from_millis(100));
+
+        assert!(health.is_available(now));
+        assert_eq!(health.failures, 0);
+        assert_eq!(health.latency, Some(Duration::from_millis(100)));
+    }
+}
@@ -0,0 +1,746 @@
+//! Direct access to DNS messages in wire format (RFC 1035 4.1).
+//!
+//! Responses from upstream servers are relayed to the client as they are, so that records the
+//! parser doesn't understand (such as RRSIG and NSEC) survive the trip. The few changes we need
+//! to make to a response are made directly on the bytes instead.
+
+use std::{
+    fmt::{self, Display},
+    ops::Range,
+};
+
+/// The size of the fixed message header.
+pub const HEADER_SIZE: usize = 12;
+
+const TYPE_SOA: u16 = 6;
+const TYPE_OPT: u16 = 41;
+
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_NO_ERROR: u8 = 0;
+pub const RCODE_FORMAT_ERROR: u8 = 1;
+pub const RCODE_SERVER_FAILURE: u8 = 2;
+pub const RCODE_NX_DOMAIN: u8 = 3;
+pub const RCODE_NOT_IMPLEMENTED: u8 = 4;
+pub const RCODE_REFUSED: u8 = 5;
+
+const FLAG_QR: u8 = 0x80;
+const FLAG_OPCODE: u8 = 0x78;
+const FLAG_TC: u8 = 0x02;
+const FLAG_RD: u8 = 0x01;
+const FLAG_RA: u8 = 0x80;
+
+/// The DNSSEC OK bit, in the TTL field of an OPT record (RFC 3225 3).
+const EDNS_FLAG_DO: u32 = 0x8000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WireError(&'static str);
+
+impl Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed message: {}", self.0)
+    }
+}
+
+impl std::error::Error for WireError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+/// The location and fixed fields of a single resource record within a message.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub section: Section,
+    pub r#type: u16,
+    pub ttl: u32,
+    /// Where the TTL is in the message, so it can be changed in place
+    pub ttl_offset: usize,
+    /// The range of the entire record, from its owner name to the end of its data
+    pub range: Range<usize>,
+}
+
+/// The EDNS(0) parameters to advertise in the OPT record of a response.
+#[derive(Debug, Clone, Copy)]
+pub struct Edns {
+    pub payload_size: u16,
+    pub dnssec_ok: bool,
+    /// The upper 8 bits of the extended RCODE, e.g. 1 for BADVERS
+    pub extended_rcode: u8,
+}
+
+/// The EDNS(0) parameters from the OPT record of a query (RFC 6891 6.1.2). Its options aren't
+/// read, since we don't support any of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Opt {
+    pub payload_size: u16,
+    pub version: u8,
+    pub dnssec_ok: bool,
+}
+
+/// The question of a query, read straight from the wire so that any type and class can be asked
+/// for, including the ones we don't know about (RFC 3597).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    /// The name in presentation format, without the trailing dot
+    pub name: String,
+    pub r#type: u16,
+    pub class: u16,
+    /// The question as it was encoded, which is sent upstream as is
+    pub encoded: Vec<u8>,
+}
+
+impl Question {
+    /// Builds an `IN` class question, e.g. for `swiftdns resolve`.
+    pub fn new(name: &str, r#type: u16) -> Result<Question, WireError> {
+        let mut encoded = Vec::new();
+
+        for label in name
+            .trim_end_matches('.')
+            .split('.')
+            .filter(|label| !label.is_empty())
+        {
+            let length = u8::try_from(label.len())
+                .ok()
+                .filter(|length| *length < 64)
+                .ok_or(WireError("label is too long"))?;
+
+            encoded.push(length);
+            encoded.extend_from_slice(label.as_bytes());
+        }
+
+        encoded.push(0);
+
+        if encoded.len() > 255 {
+            return Err(WireError("name is too long"));
+        }
+
+        encoded.extend_from_slice(&r#type.to_be_bytes());
+        encoded.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+        let mut message = vec![0; HEADER_SIZE];
+        write_u16(&mut message, 4, 1);
+        message.extend_from_slice(&encoded);
+
+        question(&message)
+    }
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Result<u16, WireError> {
+    match message.get(offset..offset + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err(WireError("unexpected end of message")),
+    }
+}
+
+fn read_u32(message: &[u8], offset: usize) -> Result<u32, WireError> {
+    match message.get(offset..offset + 4) {
+        Some(bytes) => Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+        None => Err(WireError("unexpected end of message")),
+    }
+}
+
+fn write_u16(message: &mut [u8], offset: usize, value: u16) {
+    message[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+fn write_u32(message: &mut [u8], offset: usize, value: u32) {
+    message[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn check_header(message: &[u8]) -> Result<(), WireError> {
+    if message.len() < HEADER_SIZE {
+        return Err(WireError("message is shorter than its header"));
+    }
+
+    Ok(())
+}
+
+pub fn id(message: &[u8]) -> Result<u16, WireError> {
+    read_u16(message, 0)
+}
+
+pub fn set_id(message: &mut [u8], id: u16) -> Result<(), WireError> {
+    check_header(message)?;
+    write_u16(message, 0, id);
+
+    Ok(())
+}
+
+pub fn is_response(message: &[u8]) -> Result<bool, WireError> {
+    check_header(message)?;
+
+    Ok(message[2] & FLAG_QR != 0)
+}
+
+pub fn is_truncated(message: &[u8]) -> Result<bool, WireError> {
+    check_header(message)?;
+
+    Ok(message[2] & FLAG_TC != 0)
+}
+
+pub fn rcode(message: &[u8]) -> Result<u8, WireError> {
+    check_header(message)?;
+
+    Ok(message[3] & 0x0f)
+}
+
+pub fn answer_count(message: &[u8]) -> Result<u16, WireError> {
+    read_u16(message, 6)
+}
+
+/// Returns the offset right after the (possibly compressed) domain name starting at `offset`.
+fn skip_name(message: &[u8], mut offset: usize) -> Result<usize, WireError> {
+    loop {
+        let length = *message
+            .get(offset)
+            .ok_or(WireError("unexpected end of name"))?;
+
+        match length & 0xc0 {
+            // A pointer always ends the name
+            0xc0 => return Ok(offset + 2),
+            0x00 if length == 0 => return Ok(offset + 1),
+            0x00 => offset += 1 + usize::from(length),
+            _ => return Err(WireError("invalid label type")),
+        }
+    }
+}
+
+/// Reads the question of a query, which has to be the only one. Practically no server supports
+/// more than one question per query, so neither do we.
+pub fn question(message: &[u8]) -> Result<Question, WireError> {
+    check_header(message)?;
+
+    if read_u16(message, 4)? != 1 {
+        return Err(WireError("query doesn't have exactly one question"));
+    }
+
+    let mut name = String::new();
+    let mut offset = HEADER_SIZE;
+
+    loop {
+        let length = usize::from(
+            *message
+                .get(offset)
+                .ok_or(WireError("unexpected end of name"))?,
+        );
+
+        if length == 0 {
+            offset += 1;
+            break;
+        }
+
+        // There's nothing before the question for a pointer to refer to
+        if length > 63 {
+            return Err(WireError("invalid label in question"));
+        }
+
+        let label = message
+            .get(offset + 1..offset + 1 + length)
+            .ok_or(WireError("unexpected end of name"))?;
+
+        if !name.is_empty() {
+            name.push('.');
+        }
+
+        for &byte in label {
+            match byte {
+                b'.' | b'\\' => {
+                    name.push('\\');
+                    name.push(char::from(byte));
+                }
+                0x21..=0x7e => name.push(char::from(byte)),
+                _ => name.push_str(&format!("\\{:03}", byte)),
+            }
+        }
+
+        offset += 1 + length;
+    }
+
+    if offset - HEADER_SIZE > 255 {
+        return Err(WireError("name is too long"));
+    }
+
+    Ok(Question {
+        name,
+        r#type: read_u16(message, offset)?,
+        class: read_u16(message, offset + 2)?,
+        encoded: message[HEADER_SIZE..offset + 4].to_vec(),
+    })
+}
+
+/// Reads the OPT record of a query, if it has one.
+pub fn opt(message: &[u8]) -> Result<Option<Opt>, WireError> {
+    let record = records(message)?
+        .into_iter()
+        .find(|record| record.section == Section::Additional && record.r#type == TYPE_OPT);
+
+    let Some(record) = record else {
+        return Ok(None);
+    };
+
+    // The class field holds the payload size, and the TTL field the extended RCODE, version and
+    // flags (RFC 6891 6.1.3)
+    let payload_size = read_u16(message, skip_name(message, record.range.start)? + 2)?;
+
+    Ok(Some(Opt {
+        payload_size,
+        version: (record.ttl >> 16) as u8,
+        dnssec_ok: record.ttl & EDNS_FLAG_DO != 0,
+    }))
+}
+
+/// Builds a query for a question with an ID of 0, asking for recursion and advertising EDNS.
+pub fn query(question: &Question, edns: Edns) -> Vec<u8> {
+    let mut message = vec![0; HEADER_SIZE];
+
+    message[2] = FLAG_RD;
+    write_u16(&mut message, 4, 1);
+    write_u16(&mut message, 10, 1);
+
+    message.extend_from_slice(&question.encoded);
+    message.extend_from_slice(&opt_record(edns));
+
+    message
+}
+
+/// Builds a response to a query without any records, e.g. to refuse it.
+pub fn empty_response(query: &[u8], rcode: u8) -> Result<Vec<u8>, WireError> {
+    let mut response = query[..question_end(query)?].to_vec();
+
+    response[2] = FLAG_QR | (query[2] & (FLAG_OPCODE | FLAG_RD));
+    response[3] = FLAG_RA | rcode;
+    write_u16(&mut response, 6, 0);
+    write_u16(&mut response, 8, 0);
+    write_u16(&mut response, 10, 0);
+
+    Ok(response)
+}
+
+/// Builds a FORMERR response for a query that can't be read, with only the header.
+///
+/// Returns `None` if there isn't even a full header to reply to, or if the message isn't a query.
+pub fn format_error(query: &[u8]) -> Option<Vec<u8>> {
+    if query.len() < HEADER_SIZE || query[2] & FLAG_QR != 0 {
+        return None;
+    }
+
+    let mut response = query[..HEADER_SIZE].to_vec();
+
+    response[2] = FLAG_QR | (query[2] & (FLAG_OPCODE | FLAG_RD));
+    response[3] = FLAG_RA | RCODE_FORMAT_ERROR;
+    response[4..].fill(0);
+
+    Some(response)
+}
+
+/// Returns the offset of the first byte after the question section.
+pub fn question_end(message: &[u8]) -> Result<usize, WireError> {
+    let count = read_u16(message, 4)?;
+    let mut offset = HEADER_SIZE;
+
+    for _ in 0..count {
+        offset = skip_name(message, offset)? + 4;
+    }
+
+    if offset > message.len() {
+        return Err(WireError("unexpected end of question"));
+    }
+
+    Ok(offset)
+}
+
+/// Checks whether two messages ask the same questions, ignoring the case of the names.
+pub fn same_question(message: &[u8], other: &[u8]) -> Result<bool, WireError> {
+    let question = &message[HEADER_SIZE..question_end(message)?];
+    let other_question = &other[HEADER_SIZE..question_end(other)?];
+
+    // Label lengths are at most 63, so they can't be mistaken for letters
+    Ok(read_u16(message, 4)? == read_u16(other, 4)?
+        && question.eq_ignore_ascii_case(other_question))
+}
+
+/// Walks the message and returns every resource record after the question section.
+pub fn records(message: &[u8]) -> Result<Vec<Record>, WireError> {
+    let sections = [
+        (Section::Answer, read_u16(message, 6)?),
+        (Section::Authority, read_u16(message, 8)?),
+        (Section::Additional, read_u16(message, 10)?),
+    ];
+
+    let mut offset = question_end(message)?;
+    let mut records = Vec::new();
+
+    for (section, count) in sections {
+        for _ in 0..count {
+            let start = offset;
+            let fields = skip_name(message, offset)?;
+
+            let r#type = read_u16(message, fields)?;
+            let ttl = read_u32(message, fields + 4)?;
+            let rdata_length = read_u16(message, fields + 8)?;
+
+            offset = fields + 10 + usize::from(rdata_length);
+
+            if offset > message.len() {
+                return Err(WireError("unexpected end of record data"));
+            }
+
+            records.push(Record {
+                section,
+                r#type,
+                ttl,
+                ttl_offset: fields + 4,
+                range: start..offset,
+            });
+        }
+    }
+
+    Ok(records)
+}
+
+/// The lowest TTL of the records in the answer section, which is how long the answer may be
+/// cached for (RFC 2181 5.2).
+pub fn min_answer_ttl(message: &[u8]) -> Result<Option<u32>, WireError> {
+    Ok(records(message)?
+        .iter()
+        .filter(|record| record.section == Section::Answer)
+        .map(|record| record.ttl)
+        .min())
+}
+
+/// How long a response saying the name (NXDOMAIN) or record (NODATA) doesn't exist may be cached
+/// for, which is the lower of the TTL of the SOA record in the authority section and its MINIMUM
+/// field (RFC 2308 5). Without an SOA record, it may not be cached at all.
+pub fn negative_ttl(message: &[u8]) -> Result<Option<u32>, WireError> {
+    let soa = records(message)?
+        .into_iter()
+        .find(|record| record.section == Section::Authority && record.r#type == TYPE_SOA);
+
+    let soa = match soa {
+        Some(soa) => soa,
+        None => return Ok(None),
+    };
+
+    // The MINIMUM field comes last, after MNAME, RNAME and four other 32-bit fields
+    let rdata = skip_name(message, soa.range.start)? + 10;
+    let minimum_offset = skip_name(message, skip_name(message, rdata)?)? + 16;
+
+    if minimum_offset + 4 > soa.range.end {
+        return Err(WireError("unexpected end of SOA record"));
+    }
+
+    Ok(Some(soa.ttl.min(read_u32(message, minimum_offset)?)))
+}
+
+/// Raises the TTL of every record to at least `min` seconds and lowers it to at most `max` seconds,
+/// where `max` wins if it's the lower of the two.
+pub fn clamp_ttls(message: &mut [u8], min: u32, max: u32) -> Result<(), WireError> {
+    for record in records(message)? {
+        let ttl = record.ttl.max(min).min(max);
+
+        if record.r#type != TYPE_OPT && ttl != record.ttl {
+            write_u32(message, record.ttl_offset, ttl);
+        }
+    }
+
+    Ok(())
+}
+
+/// Reduces the TTL of every record by `elapsed` seconds, for a response that has been kept that
+/// long. TTLs don't go below zero.
+pub fn age(message: &mut [u8], elapsed: u32) -> Result<(), WireError> {
+    for record in records(message)? {
+        // The TTL field of an OPT record holds flags instead (RFC 6891 6.1.3)
+        if record.r#type == TYPE_OPT {
+            continue;
+        }
+
+        write_u32(
+            message,
+            record.ttl_offset,
+            record.ttl.saturating_sub(elapsed),
+        );
+    }
+
+    Ok(())
+}
+
+/// Encodes an OPT pseudo-record (RFC 6891 6.1.2) without any options.
+fn opt_record(edns: Edns) -> [u8; 11] {
+    let mut record = [0; 11];
+
+    // The owner name is the root, followed by the type
+    write_u16(&mut record, 1, TYPE_OPT);
+    write_u16(&mut record, 3, edns.payload_size);
+
+    record[5] = edns.extended_rcode;
+
+    if edns.dnssec_ok {
+        record[7] = 0x80;
+    }
+
+    record
+}
+
+/// Adapts a response (from an upstream server or made by us) for the client that sent `query`.
+///
+/// The ID, RD flag and question are taken from the query, any OPT record in the response is
+/// replaced by our own (if the client supports EDNS), and the response is truncated if it
+/// doesn't fit in `max_size` bytes.
+pub fn finalize(
+    response: &[u8],
+    query: &[u8],
+    edns: Option<Edns>,
+    max_size: usize,
+) -> Result<Vec<u8>, WireError> {
+    let records = records(response)?;
+    let response_question_end = question_end(response)?;
+    let query_question_end = question_end(query)?;
+
+    let mut message = Vec::with_capacity(response.len());
+
+    message.extend_from_slice(&response[..HEADER_SIZE]);
+
+    // The client's question is echoed back as it was asked, since some clients randomize the case
+    // of the name (draft-vixie-dnsext-dns0x20). It can only be swapped if it has the same length,
+    // otherwise the compression pointers in the rest of the response would be off.
+    if query_question_end == response_question_end && read_u16(query, 4)? == read_u16(response, 4)?
+    {
+        message.extend_from_slice(&query[HEADER_SIZE..query_question_end]);
+    } else {
+        message.extend_from_slice(&response[HEADER_SIZE..response_question_end]);
+    }
+
+    let mut additional_count = 0;
+
+    for record in &records {
+        if record.r#type == TYPE_OPT {
+            continue;
+        }
+
+        if record.section == Section::Additional {
+            additional_count += 1;
+        }
+
+        message.extend_from_slice(&response[record.range.clone()]);
+    }
+
+    if let Some(edns) = edns {
+        message.extend_from_slice(&opt_record(edns));
+        additional_count += 1;
+    }
+
+    write_u16(&mut message, 0, id(query)?);
+    write_u16(&mut message, 10, additional_count);
+
+    message[2] = (message[2] & !FLAG_RD) | (query[2] & FLAG_RD);
+
+    if message.len() > max_size {
+        return Ok(truncate(&message, edns));
+    }
+
+    Ok(message)
+}
+
+/// Strips every record from a message and sets the TC bit, so the client retries over TCP.
+///
+/// The OPT record is kept even in truncated responses (RFC 6891 7).
+fn truncate(message: &[u8], edns: Option<Edns>) -> Vec<u8> {
+    let question_end = question_end(message).unwrap_or(HEADER_SIZE);
+
+    let mut truncated = message[..question_end].to_vec();
+
+    truncated[2] |= FLAG_TC;
+    write_u16(&mut truncated, 6, 0);
+    write_u16(&mut truncated, 8, 0);
+    write_u16(&mut truncated, 10, 0);
+
+    if let Some(edns) = edns {
+        truncated.extend_from_slice(&opt_record(edns));
+        write_u16(&mut truncated, 10, 1);
+    }
+
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use dns_message_parser::{
+        rr::{Class, RR, TXT},
+        Dns, RCode,
+    };
+
+    use super::{
+        age, clamp_ttls, finalize, format_error, min_answer_ttl, negative_ttl, opt, opt_record,
+        question, records, same_question, Edns, Section, RCODE_FORMAT_ERROR,
+    };
+    use crate::testing::{a, message, query, soa};
+
+    /// A response with the answers to an `A` question for `example.com`.
+    fn response(answers: Vec<RR>) -> Vec<u8> {
+        message("example.com", RCode::NoError, answers, Vec::new())
+    }
+
+    #[test]
+    fn walks_records() {
+        let response = response(vec![a("example.com", 300), a("example.com", 60)]);
+        let records = records(&response).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].section, Section::Answer);
+        assert_eq!(records[1].ttl, 60);
+        assert_eq!(records[1].range.end, response.len());
+    }
+
+    #[test]
+    fn counts_down_ttls() {
+        let mut response = response(vec![a("example.com", 300), a("example.com", 60)]);
+
+        assert_eq!(min_answer_ttl(&response).unwrap(), Some(60));
+
+        age(&mut response, 100).unwrap();
+
+        let ttls: Vec<u32> = records(&response)
+            .unwrap()
+            .iter()
+            .map(|record| record.ttl)
+            .collect();
+
+        assert_eq!(ttls, [200, 0]);
+    }
+
+    #[test]
+    fn finds_negative_ttl() {
+        let authorities = vec![soa(3600, 900)];
+        let mut response = message("nope.example", RCode::NXDomain, Vec::new(), authorities);
+
+        assert_eq!(negative_ttl(&response).unwrap(), Some(900));
+
+        clamp_ttls(&mut response, 0, 60).unwrap();
+
+        assert_eq!(negative_ttl(&response).unwrap(), Some(60));
+
+        let response = message("nope.example", RCode::NXDomain, Vec::new(), Vec::new());
+
+        assert_eq!(negative_ttl(&response).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let response = response(vec![a("example.com", 300)]);
+
+        assert!(records(&response[..response.len() - 1]).is_err());
+        assert!(records(&response[..5]).is_err());
+    }
+
+    #[test]
+    fn compares_questions() {
+        let (query, other) = (query("ExAmPlE.com", 1, None), query("example.org", 1, None));
+
+        assert!(same_question(&query, &response(vec![a("example.com", 300)])).unwrap());
+        assert!(!same_question(&query, &other).unwrap());
+    }
+
+    #[test]
+    fn reads_questions() {
+        let query = query("ExAmPlE.com", 1, None);
+        let read = question(&query).unwrap();
+
+        assert_eq!(read.name, "ExAmPlE.com");
+        assert_eq!((read.r#type, read.class), (1, 1));
+        assert_eq!(read, super::Question::new("ExAmPlE.com.", 1).unwrap());
+
+        // Labels can hold any byte, those that aren't printable are escaped
+        let mut query = query[..12].to_vec();
+        query.extend_from_slice(b"\x03a.\x01\x07example\x00\x00\x01\x00\x01");
+
+        assert_eq!(question(&query).unwrap().name, "a\\.\\001.example");
+
+        assert!(question(&query[..query.len() - 1]).is_err());
+        assert!(super::Question::new(&"a".repeat(64), 1).is_err());
+    }
+
+    #[test]
+    fn reads_edns() {
+        let mut query = query("example.com", 1, None);
+
+        assert_eq!(opt(&query).unwrap(), None);
+
+        let edns = Edns {
+            payload_size: 4096,
+            dnssec_ok: true,
+            extended_rcode: 0,
+        };
+
+        query.extend_from_slice(&opt_record(edns));
+        query[11] = 1;
+
+        let read = opt(&query).unwrap().unwrap();
+
+        assert_eq!(read.payload_size, 4096);
+        assert_eq!(read.version, 0);
+        assert!(read.dnssec_ok);
+    }
+
+    #[test]
+    fn answers_malformed_queries() {
+        let query = query("example.com", 1, None);
+        let response = format_error(&query[..14]).unwrap();
+
+        assert_eq!(response.len(), 12);
+        assert_eq!(&response[..2], &query[..2]);
+        assert_eq!(response[3] & 0x0f, RCODE_FORMAT_ERROR);
+
+        assert!(format_error(&query[..11]).is_none());
+        assert!(format_error(&response).is_none());
+    }
+
+    #[test]
+    fn adapts_response_to_query() {
+        let query = query("ExAmPlE.com", 1, None);
+        let response = response(vec![a("example.com", 300)]);
+
+        let edns = Edns {
+            payload_size: 1232,
+            dnssec_ok: false,
+            extended_rcode: 0,
+        };
+
+        let finalized = finalize(&response, &query, Some(edns), 512).unwrap();
+        let dns = Dns::decode(finalized.into()).unwrap();
+
+        assert_eq!(dns.id, 0x1234);
+        assert_eq!(dns.questions[0].domain_name.to_string(), "ExAmPlE.com.");
+        assert_eq!(dns.answers.len(), 1);
+        assert!(matches!(dns.additionals[..], [RR::OPT(_)]));
+    }
+
+    #[test]
+    fn truncates_large_responses() {
+        let query = query("example.com", 1, None);
+
+        let text = RR::TXT(TXT {
+            domain_name: "example.com".parse().unwrap(),
+            ttl: 300,
+            class: Class::IN,
+            strings: vec!["x".repeat(255); 4].try_into().unwrap(),
+        });
+
+        let response = response(vec![text]);
+
+        let finalized = finalize(&response, &query, None, 512).unwrap();
+        let dns = Dns::decode(finalized.into()).unwrap();
+
+        assert!(dns.flags.tc);
+        assert!(dns.answers.is_empty());
+        assert_eq!(dns.questions.len(), 1);
+
+        let finalized = finalize(&response, &query, None, u16::MAX.into()).unwrap();
+
+        assert!(!Dns::decode(finalized.into()).unwrap().flags.tc);
+    }
+}